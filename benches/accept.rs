@@ -0,0 +1,98 @@
+//! compare the single listener with `SO_REUSEPORT` acceptors
+//!
+//! `cargo bench --bench accept`, each client opens tunnels in a loop, the latency
+//! is measured from connect to the `200` response
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use proxies::connector::DirectConnector;
+use proxies::server::ProxyServer;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const CLIENTS: usize = 64;
+const TUNNELS_PER_CLIENT: usize = 200;
+
+async fn target() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((sock, _)) = listener.accept().await {
+            drop(sock);
+        }
+    });
+    addr
+}
+
+async fn tunnel(proxy: SocketAddr, request: &[u8]) -> Duration {
+    let start = Instant::now();
+    let mut sock = TcpStream::connect(proxy).await.unwrap();
+    sock.write_all(request).await.unwrap();
+    let mut buf = [0; 19];
+    sock.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"HTTP/1.1 200 Ok\r\n\r\n");
+    start.elapsed()
+}
+
+async fn run(name: &str, proxy: SocketAddr, target: SocketAddr) {
+    let request = format!("CONNECT {target} HTTP/1.1\r\n\r\n").into_bytes();
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            let request = request.clone();
+            tokio::spawn(async move {
+                let mut latencies = Vec::with_capacity(TUNNELS_PER_CLIENT);
+                for _ in 0..TUNNELS_PER_CLIENT {
+                    latencies.push(tunnel(proxy, &request).await);
+                }
+                latencies
+            })
+        })
+        .collect();
+    let mut latencies = Vec::new();
+    for client in clients {
+        latencies.extend(client.await.unwrap());
+    }
+    let elapsed = start.elapsed();
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{:<16} {:>8.0} conn/s  p50 {:>10?}  p99 {:>10?}",
+        name,
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        percentile(50),
+        percentile(99),
+    );
+}
+
+fn main() {
+    let workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let target = target().await;
+
+        let server = ProxyServer::bind(DirectConnector, "127.0.0.1:0")
+            .await
+            .unwrap();
+        let proxy = server.incoming().local_addr().unwrap();
+        tokio::spawn(server.run());
+        run("single", proxy, target).await;
+
+        #[cfg(unix)]
+        {
+            let server = ProxyServer::bind_reuseport(DirectConnector, "127.0.0.1:0", workers)
+                .await
+                .unwrap();
+            let proxy = server.incoming().local_addr();
+            tokio::spawn(server.run());
+            run(&format!("reuseport x{workers}"), proxy, target).await;
+        }
+    });
+}
@@ -1,5 +1,7 @@
 extern crate proxies;
 
+use std::time::Duration;
+
 use proxies::connector::DirectConnector;
 use proxies::server::ProxyServer;
 
@@ -9,5 +11,12 @@ async fn main() {
     let server = ProxyServer::bind(DirectConnector, "127.0.0.1:9000")
         .await
         .expect("bind fail");
-    server.run().await.expect("run server fail");
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown.shutdown(Duration::from_secs(30));
+        }
+    });
+    let summary = server.run().await.expect("run server fail");
+    println!("{summary:?}");
 }
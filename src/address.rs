@@ -23,6 +23,13 @@ impl Address {
         }
     }
 
+    pub fn host(&self) -> String {
+        match self {
+            Self::Domain(host, _) => host.clone(),
+            Self::Sock(addr) => addr.ip().to_string(),
+        }
+    }
+
     pub fn port(&self) -> u16 {
         match self {
             Self::Domain(_, port) => *port,
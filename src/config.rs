@@ -0,0 +1,930 @@
+//! configuration file of the `proxies` binary, TOML or YAML
+//!
+//! ```toml
+//! shutdown_grace = "30s"
+//!
+//! [[listeners]]
+//! name = "main"
+//! listen = "0.0.0.0:1080"
+//! protocols = ["socks5", "http"]
+//!
+//! [auth]
+//! users = [{ name = "alice", password = "secret" }]
+//!
+//! [acl]
+//! default = "allow"
+//! rules = [{ action = "deny", ips = ["10.0.0.0/8"], ports = [22, "6000-7000"] }]
+//!
+//! [[outbounds]]
+//! name = "direct"
+//! type = "direct"
+//! ssrf_guard = { allowed = ["10.1.0.0/16"] }
+//!
+//! [routing]
+//! default = "direct"
+//!
+//! [[rewrites]]
+//! from = "*.prod.example.com"
+//! to = "*.staging.example.com"
+//!
+//! [timeouts]
+//! handshake = "10s"
+//! idle = "5m"
+//!
+//! [log]
+//! level = "info"
+//! access_log = "/var/log/proxies/access.log"
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ProxyError;
+use crate::address::Address;
+use crate::connector::{Connector, DirectConnector, Route, Router, SsrfGuard};
+use crate::server::{
+    Acl, Action, ConnectionLimits, Credentials, Overflow, Rewrite, RewriteRule, Rule, Timeouts,
+};
+use crate::util::Cidr;
+
+#[cfg(unix)]
+mod build;
+
+#[cfg(unix)]
+pub use build::Built;
+
+/// protocols served by a listener without `protocols`
+const DEFAULT_PROTOCOLS: [&str; 2] = ["socks5", "http"];
+
+/// outbound used when no outbound is configured
+const DEFAULT_OUTBOUND: &str = "direct";
+
+/// invalid configuration, `key` is the path of the offending key, e.g. `listeners[0].listen`
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.key.as_str() {
+            "" | "." => write!(f, "{}", self.message),
+            key => write!(f, "{}: {}", key, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for ProxyError {
+    fn from(e: ConfigError) -> Self {
+        ProxyError::InvalidData(format!("invalid config: {e}"))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// the first listener is the primary listener of the server
+    pub listeners: Vec<ListenerConfig>,
+    pub auth: AuthConfig,
+    pub acl: Option<AclConfig>,
+    /// `direct` when empty
+    pub outbounds: Vec<OutboundConfig>,
+    pub routing: RoutingConfig,
+    /// destination rewrites, the first matching rewrite applies
+    pub rewrites: Vec<RewriteConfig>,
+    pub timeouts: TimeoutsConfig,
+    pub limits: LimitsConfig,
+    pub log: LogConfig,
+    pub metrics: Option<MetricsConfig>,
+    pub admin: Option<AdminConfig>,
+    /// time given to in-flight sessions on shutdown
+    #[serde(with = "duration_opt")]
+    pub shutdown_grace: Option<Duration>,
+    /// unix socket to pass the listeners to a new process on upgrade
+    pub handoff: Option<PathBuf>,
+    /// check the config file for changes at this interval and reload it,
+    /// SIGHUP reloads it too
+    #[serde(with = "duration_opt")]
+    pub watch: Option<Duration>,
+}
+
+/// one listening socket, a listener with the same name inherited from systemd
+/// or a previous process is used instead of binding
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// `listener<index>` when not set
+    pub name: Option<String>,
+    /// TCP address
+    pub listen: Option<Address>,
+    /// unix socket path
+    pub unix: Option<PathBuf>,
+    /// `socks5` and `http` when empty
+    pub protocols: Vec<String>,
+    /// require credentials, defaults to true when users are configured
+    pub auth: Option<bool>,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// require client certificates signed by this CA, the certificate
+    /// common name authenticates the user
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub users: Vec<UserConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionConfig {
+    #[default]
+    Allow,
+    Deny,
+}
+
+impl From<ActionConfig> for Action {
+    fn from(action: ActionConfig) -> Self {
+        match action {
+            ActionConfig::Allow => Action::Allow,
+            ActionConfig::Deny => Action::Deny,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    /// applies when no rule matches
+    pub default: ActionConfig,
+    pub rules: Vec<AclRuleConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclRuleConfig {
+    pub action: ActionConfig,
+    /// client cidrs
+    pub clients: Vec<String>,
+    pub users: Vec<String>,
+    pub domains: Vec<String>,
+    /// destination cidrs
+    pub ips: Vec<String>,
+    pub ports: Vec<PortsConfig>,
+}
+
+/// a port, `443`, or an inclusive range, `"8000-9000"`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PortsConfig {
+    Port(u16),
+    Range(String),
+}
+
+impl PortsConfig {
+    fn range(&self) -> Option<RangeInclusive<u16>> {
+        match self {
+            Self::Port(port) => Some(*port..=*port),
+            Self::Range(range) => {
+                let (start, end) = range.split_once('-')?;
+                let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+                (start <= end).then_some(start..=end)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboundType {
+    #[default]
+    Direct,
+    /// unix sockets, see `UnixConnector`
+    Unix,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: OutboundType,
+    /// unix: `<name>.unix` hosts connect to `<socket_dir>/<name>.sock`
+    pub socket_dir: Option<PathBuf>,
+    /// unix: host to socket path
+    pub hosts: BTreeMap<String, PathBuf>,
+    /// direct: resolve and refuse special-purpose addresses, see `SsrfGuard`
+    pub ssrf_guard: Option<SsrfGuardConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SsrfGuardConfig {
+    pub allowed: Vec<String>,
+    pub blocked: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    /// the first outbound when not set
+    pub default: Option<String>,
+    /// the first matching route decides
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConfig {
+    pub outbound: String,
+    pub domains: Vec<String>,
+    pub ips: Vec<String>,
+    pub ports: Vec<PortsConfig>,
+}
+
+/// connect to `to` instead of `from`, see `RewriteRule`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RewriteConfig {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    #[serde(with = "duration_opt")]
+    pub handshake: Option<Duration>,
+    #[serde(with = "duration_opt")]
+    pub connect: Option<Duration>,
+    #[serde(with = "duration_opt")]
+    pub idle: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_sessions: Option<usize>,
+    pub max_sessions_per_ip: Option<usize>,
+    pub accept_rate_per_ip: Option<u32>,
+    /// wait this long for a free slot instead of closing connections over the limits
+    #[serde(with = "duration_opt")]
+    pub queue: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`
+    pub level: String,
+    /// JSON lines access log
+    pub access_log: Option<PathBuf>,
+    /// rotate the access log at this size
+    pub access_log_max_bytes: Option<u64>,
+    /// rotated access logs kept
+    pub access_log_keep: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            access_log: None,
+            access_log_max_bytes: None,
+            access_log_keep: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// serve `GET /metrics` on this address
+    pub listen: Address,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// serve the JSON admin API on this address, see `Admin`
+    pub listen: Address,
+    /// required as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+}
+
+/// replaces secrets in `Config::redacted`
+const REDACTED: &str = "<redacted>";
+
+impl Config {
+    /// read `path`, `.yaml` and `.yml` files are YAML, others TOML
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::new("", format!("read {} fail: {}", path.display(), e)))?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => Self::from_yaml(&text),
+            _ => Self::from_toml(&text),
+        }
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        let config: Self = serde_path_to_error::deserialize(toml::Deserializer::new(text))
+            .map_err(|e| ConfigError::new(e.path().to_string(), e.inner().message()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_yaml(text: &str) -> Result<Self, ConfigError> {
+        let config: Self =
+            serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(text))
+                .map_err(|e| ConfigError::new(e.path().to_string(), e.inner().to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// check everything that can be checked without binding or reading files
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listeners.is_empty() {
+            return Err(ConfigError::new("listeners", "no listener"));
+        }
+        let mut names = Vec::new();
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let key = format!("listeners[{i}]");
+            let name = self.listener_name(i);
+            if name.is_empty() || name.contains(':') {
+                return Err(ConfigError::new(
+                    format!("{key}.name"),
+                    format!("invalid name {name:?}"),
+                ));
+            }
+            if names.contains(&name) {
+                return Err(ConfigError::new(
+                    format!("{key}.name"),
+                    format!("duplicate name {name:?}"),
+                ));
+            }
+            names.push(name);
+            match (&listener.listen, &listener.unix) {
+                (Some(_), None) => {}
+                (None, Some(_)) if cfg!(unix) => {}
+                (None, Some(_)) => {
+                    return Err(ConfigError::new(
+                        format!("{key}.unix"),
+                        "unix sockets are not supported on this platform",
+                    ));
+                }
+                _ => {
+                    return Err(ConfigError::new(
+                        key,
+                        "exactly one of `listen` and `unix` is required",
+                    ));
+                }
+            }
+            for (j, protocol) in listener.protocols.iter().enumerate() {
+                if !DEFAULT_PROTOCOLS.contains(&protocol.as_str()) {
+                    return Err(ConfigError::new(
+                        format!("{key}.protocols[{j}]"),
+                        format!("unknown protocol {protocol:?}"),
+                    ));
+                }
+            }
+            if listener.auth == Some(true) && self.auth.users.is_empty() {
+                return Err(ConfigError::new(
+                    format!("{key}.auth"),
+                    "auth is required but no users are configured",
+                ));
+            }
+            if listener.tls.is_some() && !cfg!(feature = "tls") {
+                return Err(ConfigError::new(
+                    format!("{key}.tls"),
+                    "TLS requires the `tls` feature",
+                ));
+            }
+        }
+        self.credentials()?;
+        self.acl()?;
+        self.router()?;
+        self.rewrite()?;
+        if !matches!(
+            self.log.level.as_str(),
+            "off" | "error" | "warn" | "info" | "debug" | "trace"
+        ) {
+            return Err(ConfigError::new(
+                "log.level",
+                format!("unknown level {:?}", self.log.level),
+            ));
+        }
+        Ok(())
+    }
+
+    /// copy without passwords and tokens, e.g. to show the running config
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        for user in &mut config.auth.users {
+            user.password = REDACTED.to_string();
+        }
+        if let Some(token) = config.admin.as_mut().and_then(|a| a.token.as_mut()) {
+            *token = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// top level keys changed by `new` that only take effect after a restart,
+    /// reloading applies auth, acl, outbounds, routing and the log level
+    pub fn restart_keys(&self, new: &Config) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        if differs(&self.listeners, &new.listeners) {
+            keys.push("listeners");
+        }
+        if differs(&self.rewrites, &new.rewrites) {
+            keys.push("rewrites");
+        }
+        if differs(&self.timeouts, &new.timeouts) {
+            keys.push("timeouts");
+        }
+        if differs(&self.limits, &new.limits) {
+            keys.push("limits");
+        }
+        if differs(&self.metrics, &new.metrics) {
+            keys.push("metrics");
+        }
+        if differs(&self.admin, &new.admin) {
+            keys.push("admin");
+        }
+        let access_log = |log: &LogConfig| {
+            (
+                log.access_log.clone(),
+                log.access_log_max_bytes,
+                log.access_log_keep,
+            )
+        };
+        if access_log(&self.log) != access_log(&new.log) {
+            keys.push("log");
+        }
+        if self.handoff != new.handoff {
+            keys.push("handoff");
+        }
+        keys
+    }
+
+    pub fn listener_name(&self, index: usize) -> String {
+        match &self.listeners[index].name {
+            Some(name) => name.clone(),
+            None => format!("listener{index}"),
+        }
+    }
+
+    /// credentials of the listeners requiring auth, `None` without users
+    pub fn credentials(&self) -> Result<Option<Credentials>, ConfigError> {
+        if self.auth.users.is_empty() {
+            return Ok(None);
+        }
+        let mut credentials = Credentials::new();
+        for (i, user) in self.auth.users.iter().enumerate() {
+            if user.name.is_empty() {
+                return Err(ConfigError::new(
+                    format!("auth.users[{i}].name"),
+                    "empty name",
+                ));
+            }
+            credentials = credentials.with_user(&user.name, &user.password);
+            if credentials.len() != i + 1 {
+                return Err(ConfigError::new(
+                    format!("auth.users[{i}].name"),
+                    format!("duplicate user {:?}", user.name),
+                ));
+            }
+        }
+        Ok(Some(credentials))
+    }
+
+    pub fn acl(&self) -> Result<Option<Acl>, ConfigError> {
+        let Some(config) = &self.acl else {
+            return Ok(None);
+        };
+        let mut acl = Acl::new(config.default.into());
+        for (i, rule_config) in config.rules.iter().enumerate() {
+            let key = format!("acl.rules[{i}]");
+            let mut rule = Rule::new(rule_config.action.into());
+            for (j, cidr) in rule_config.clients.iter().enumerate() {
+                rule = rule.with_client(parse_cidr(&format!("{key}.clients[{j}]"), cidr)?);
+            }
+            for user in &rule_config.users {
+                rule = rule.with_user(user);
+            }
+            for domain in &rule_config.domains {
+                rule = rule.with_domain(domain);
+            }
+            for (j, cidr) in rule_config.ips.iter().enumerate() {
+                rule = rule.with_ip(parse_cidr(&format!("{key}.ips[{j}]"), cidr)?);
+            }
+            for (j, ports) in rule_config.ports.iter().enumerate() {
+                rule = rule.with_ports(parse_ports(&format!("{key}.ports[{j}]"), ports)?);
+            }
+            acl = acl.with_rule(rule);
+        }
+        Ok(Some(acl))
+    }
+
+    /// outbounds and routes, connectors are created but not connected
+    pub fn router(&self) -> Result<Router, ConfigError> {
+        let mut outbounds = Vec::new();
+        for (i, config) in self.outbounds.iter().enumerate() {
+            let key = format!("outbounds[{i}]");
+            if config.name.is_empty() {
+                return Err(ConfigError::new(format!("{key}.name"), "empty name"));
+            }
+            if outbounds.iter().any(|(name, _)| name == &config.name) {
+                return Err(ConfigError::new(
+                    format!("{key}.name"),
+                    format!("duplicate outbound {:?}", config.name),
+                ));
+            }
+            outbounds.push((config.name.clone(), outbound(&key, config)?));
+        }
+        if outbounds.is_empty() {
+            outbounds.push((DEFAULT_OUTBOUND.to_string(), DirectConnector.make_arc()));
+        }
+        let default = match &self.routing.default {
+            Some(name) if !outbounds.iter().any(|(n, _)| n == name) => {
+                return Err(ConfigError::new(
+                    "routing.default",
+                    format!("unknown outbound {name:?}"),
+                ));
+            }
+            Some(name) => name.clone(),
+            None => outbounds[0].0.clone(),
+        };
+        let index = outbounds.iter().position(|(n, _)| n == &default).unwrap();
+        let (name, connector) = outbounds.remove(index);
+        let mut router = Router::new(&name, connector);
+        for (name, connector) in outbounds {
+            router = router.with_outbound(&name, connector);
+        }
+        for (i, config) in self.routing.routes.iter().enumerate() {
+            let key = format!("routing.routes[{i}]");
+            let mut route = Route::new(&config.outbound);
+            for domain in &config.domains {
+                route = route.with_domain(domain);
+            }
+            for (j, cidr) in config.ips.iter().enumerate() {
+                route = route.with_ip(parse_cidr(&format!("{key}.ips[{j}]"), cidr)?);
+            }
+            for (j, ports) in config.ports.iter().enumerate() {
+                route = route.with_ports(parse_ports(&format!("{key}.ports[{j}]"), ports)?);
+            }
+            router = router.with_route(route).map_err(|_| {
+                ConfigError::new(
+                    format!("{key}.outbound"),
+                    format!("unknown outbound {:?}", config.outbound),
+                )
+            })?;
+        }
+        Ok(router)
+    }
+
+    /// `None` without rewrites
+    pub fn rewrite(&self) -> Result<Option<Rewrite>, ConfigError> {
+        if self.rewrites.is_empty() {
+            return Ok(None);
+        }
+        let mut rewrite = Rewrite::new();
+        for (i, config) in self.rewrites.iter().enumerate() {
+            let rule = RewriteRule::new(&config.from, &config.to)
+                .map_err(|e| ConfigError::new(format!("rewrites[{i}]"), e.to_string()))?;
+            rewrite = rewrite.with_rule(rule);
+        }
+        Ok(Some(rewrite))
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            handshake: self.timeouts.handshake,
+            connect: self.timeouts.connect,
+            idle: self.timeouts.idle,
+        }
+    }
+
+    pub fn limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_sessions: self.limits.max_sessions,
+            max_sessions_per_ip: self.limits.max_sessions_per_ip,
+            overflow: match self.limits.queue {
+                Some(wait) => Overflow::Queue(wait),
+                None => Overflow::Reject,
+            },
+            accept_rate_per_ip: self.limits.accept_rate_per_ip,
+        }
+    }
+}
+
+fn outbound(
+    key: &str,
+    config: &OutboundConfig,
+) -> Result<crate::connector::ArcConnector, ConfigError> {
+    let unix_only = config.socket_dir.is_some() || !config.hosts.is_empty();
+    match config.kind {
+        OutboundType::Direct if unix_only => Err(ConfigError::new(
+            key,
+            "`socket_dir` and `hosts` require `type = \"unix\"`",
+        )),
+        OutboundType::Direct => match &config.ssrf_guard {
+            Some(guard_config) => {
+                let mut guard = SsrfGuard::new(DirectConnector);
+                for (j, cidr) in guard_config.allowed.iter().enumerate() {
+                    guard = guard
+                        .with_allowed(parse_cidr(&format!("{key}.ssrf_guard.allowed[{j}]"), cidr)?);
+                }
+                for (j, cidr) in guard_config.blocked.iter().enumerate() {
+                    guard = guard
+                        .with_blocked(parse_cidr(&format!("{key}.ssrf_guard.blocked[{j}]"), cidr)?);
+                }
+                Ok(guard.make_arc())
+            }
+            None => Ok(DirectConnector.make_arc()),
+        },
+        OutboundType::Unix if config.ssrf_guard.is_some() => Err(ConfigError::new(
+            format!("{key}.ssrf_guard"),
+            "`ssrf_guard` requires `type = \"direct\"`",
+        )),
+        #[cfg(unix)]
+        OutboundType::Unix => {
+            let mut connector = crate::connector::UnixConnector::new();
+            if let Some(dir) = &config.socket_dir {
+                connector = connector.with_socket_dir(dir);
+            }
+            for (host, path) in &config.hosts {
+                connector = connector.with_host(host, path);
+            }
+            Ok(connector.make_arc())
+        }
+        #[cfg(not(unix))]
+        OutboundType::Unix => Err(ConfigError::new(
+            format!("{key}.type"),
+            "unix sockets are not supported on this platform",
+        )),
+    }
+}
+
+fn differs<T: Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+}
+
+fn parse_cidr(key: &str, s: &str) -> Result<Cidr, ConfigError> {
+    s.parse()
+        .map_err(|_| ConfigError::new(key, format!("invalid cidr {s:?}")))
+}
+
+fn parse_ports(key: &str, ports: &PortsConfig) -> Result<RangeInclusive<u16>, ConfigError> {
+    ports
+        .range()
+        .ok_or_else(|| ConfigError::new(key, format!("invalid port range {ports:?}")))
+}
+
+/// durations as `500ms`, `30s`, `5m`, `1h` or a number of seconds
+mod duration_opt {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Secs(u64),
+        Text(String),
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<Duration> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let value: u64 = s[..split].parse().ok()?;
+        match s[split..].trim() {
+            "ms" => Some(Duration::from_millis(value)),
+            "" | "s" => Some(Duration::from_secs(value)),
+            "m" => Some(Duration::from_secs(value * 60)),
+            "h" => Some(Duration::from_secs(value * 3600)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn serialize<S>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match value {
+            Some(d) if d.subsec_millis() == 0 => {
+                serializer.serialize_str(&format!("{}s", d.as_secs()))
+            }
+            Some(d) => serializer.serialize_str(&format!("{}ms", d.as_millis())),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<Value>::deserialize(deserializer)? {
+            Some(Value::Secs(secs)) => Ok(Some(Duration::from_secs(secs))),
+            Some(Value::Text(s)) => parse(&s)
+                .map(Some)
+                .ok_or_else(|| D::Error::custom(format!("invalid duration {s:?}"))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Config;
+    use crate::address::Address;
+    use crate::server::Action;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::from_toml(
+            r#"
+            shutdown_grace = 10
+
+            [[listeners]]
+            listen = "127.0.0.1:1080"
+            protocols = ["socks5"]
+
+            [auth]
+            users = [{ name = "alice", password = "secret" }]
+
+            [acl]
+            default = "deny"
+            rules = [{ action = "allow", clients = ["10.0.0.0/8"], ports = [443, "8000-8080"] }]
+
+            [[outbounds]]
+            name = "guarded"
+            ssrf_guard = { allowed = ["10.1.0.0/16"] }
+
+            [[outbounds]]
+            name = "direct"
+
+            [routing]
+            default = "direct"
+            routes = [{ outbound = "guarded", domains = ["*.example.com"] }]
+
+            [[rewrites]]
+            from = "api.internal:443"
+            to = "10.0.0.5:8443"
+
+            [timeouts]
+            handshake = "500ms"
+            idle = "5m"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.shutdown_grace, Some(Duration::from_secs(10)));
+        assert_eq!(config.listener_name(0), "listener0");
+        assert_eq!(config.timeouts.handshake, Some(Duration::from_millis(500)));
+        assert_eq!(config.timeouts.idle, Some(Duration::from_secs(300)));
+        assert!(
+            config
+                .credentials()
+                .unwrap()
+                .unwrap()
+                .verify("alice", "secret")
+        );
+        let acl = config.acl().unwrap().unwrap();
+        let client = crate::server::ClientInfo::new("10.0.0.1:1000".parse().unwrap());
+        let target: Address = "www.example.com:8080".parse().unwrap();
+        assert_eq!(acl.check(&client, &target), Action::Allow);
+        let router = config.router().unwrap();
+        assert_eq!(router.route(&target), "guarded");
+        assert_eq!(router.route(&"example.org:80".parse().unwrap()), "direct");
+        let rewrite = config.rewrite().unwrap().unwrap();
+        let target = rewrite.rewrite(&"api.internal:443".parse().unwrap());
+        assert_eq!(target.unwrap().to_string(), "10.0.0.5:8443");
+
+        let yaml = Config::from_yaml(
+            "listeners:\n  - name: main\n    unix: /tmp/proxies.sock\ntimeouts:\n  connect: 3s\n",
+        )
+        .unwrap();
+        assert_eq!(yaml.listener_name(0), "main");
+        assert_eq!(yaml.timeouts.connect, Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("", "listeners"),
+            ("[[listeners]]\nlisten = \"nope\"", "listeners[0].listen"),
+            (
+                "[[listeners]]\nlisten = \"127.0.0.1:1\"\nport = 1",
+                "listeners[0].port",
+            ),
+            (
+                "[[listeners]]\nlisten = \"127.0.0.1:1\"\nprotocols = [\"ftp\"]",
+                "listeners[0].protocols[0]",
+            ),
+            (
+                "[[listeners]]\nlisten = \"127.0.0.1:1\"\n[timeouts]\nidle = \"5 days\"",
+                "timeouts.idle",
+            ),
+            (
+                "[[listeners]]\nlisten = \"127.0.0.1:1\"\n[acl]\nrules = [{}, { ips = [\"10/8\"] }]",
+                "acl.rules[1].ips[0]",
+            ),
+            (
+                "[[listeners]]\nlisten = \"127.0.0.1:1\"\n[acl]\nrules = [{ ports = [\"9-1\"] }]",
+                "acl.rules[0].ports[0]",
+            ),
+            (
+                "[[listeners]]\nlisten = \"127.0.0.1:1\"\n[routing]\nroutes = [{ outbound = \"vpn\" }]",
+                "routing.routes[0].outbound",
+            ),
+            (
+                "[[listeners]]\nlisten = \"127.0.0.1:1\"\n[auth]\nusers = [{ name = \"a\", password = \"\" }, { name = \"a\", password = \"\" }]",
+                "auth.users[1].name",
+            ),
+            (
+                "[[listeners]]\nlisten = \"127.0.0.1:1\"\n[[rewrites]]\nfrom = \"a.example.com\"\nto = \"*.example.org\"",
+                "rewrites[0]",
+            ),
+        ];
+        for (text, key) in cases {
+            let e = Config::from_toml(text).unwrap_err();
+            assert_eq!(e.key, key, "{text}: {e}");
+        }
+    }
+
+    #[test]
+    fn test_restart_keys() {
+        let old = Config::from_toml("[[listeners]]\nlisten = \"127.0.0.1:1\"").unwrap();
+        let new = Config::from_toml(
+            "[[listeners]]\nlisten = \"127.0.0.1:1\"\n[acl]\ndefault = \"deny\"\n[limits]\nmax_sessions = 1",
+        )
+        .unwrap();
+        assert_eq!(old.restart_keys(&new), ["limits"]);
+        assert!(old.restart_keys(&old.clone()).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_build_server() {
+        use tokio::net::TcpListener;
+
+        use crate::server::ListenFds;
+        use crate::testing::{pong_target, request};
+
+        let target_addr = pong_target().await;
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = Config::from_toml(&format!(
+            "[[listeners]]\nname = \"main\"\nlisten = \"127.0.0.1:{port}\"\nprotocols = [\"http\"]\n"
+        ))
+        .unwrap();
+        let built = config
+            .build_server(&mut ListenFds::new(Vec::new()))
+            .await
+            .unwrap();
+        assert_eq!(built.fds.len(), 1);
+        assert_eq!(built.fds[0].0, "main");
+        tokio::spawn(built.server.run());
+
+        let proxy = ([127, 0, 0, 1], port).into();
+        let data = request(proxy, target_addr).await;
+        assert_eq!(data, b"HTTP/1.1 200 Ok\r\n\r\npong");
+    }
+}
@@ -0,0 +1,146 @@
+use std::os::fd::{AsFd, OwnedFd};
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+
+use super::{Config, ConfigError, ListenerConfig};
+use crate::ProxyError;
+use crate::connector::Router;
+use crate::server::{
+    BoxedIncoming, InheritedListener, JsonLinesFile, ListenFds, Listener, Policy, ProxyServer,
+    TcpIncoming, UnixIncoming,
+};
+
+/// server built from a `Config`
+pub struct Built {
+    pub server: ProxyServer<Arc<Router>, BoxedIncoming>,
+    /// duplicated fds of all listeners by name, to pass on with `Handoff`
+    pub fds: Vec<(String, OwnedFd)>,
+}
+
+impl Config {
+    /// routing, acl and credentials of each listener in order, shares one router,
+    /// see `ReloadHandle::reload`
+    pub fn policies(&self) -> Result<Vec<Policy<Arc<Router>>>, ConfigError> {
+        let router = Arc::new(self.router()?);
+        let acl = self.acl()?;
+        let credentials = self.credentials()?;
+        let policies = self.listeners.iter().map(|config| {
+            let mut policy = Policy::new(router.clone());
+            if let Some(acl) = &acl {
+                policy = policy.with_acl(acl.clone());
+            }
+            if let Some(credentials) = &credentials
+                && config.auth.unwrap_or(true)
+            {
+                policy = policy.with_credentials(credentials.clone());
+            }
+            policy
+        });
+        Ok(policies.collect())
+    }
+
+    /// bind the listeners and build the server, listeners named like a socket in
+    /// `inherited` take it over instead of binding, must be called inside a tokio runtime
+    pub async fn build_server(&self, inherited: &mut ListenFds) -> Result<Built, ProxyError> {
+        let mut policies = self.policies()?.into_iter();
+        let mut fds = Vec::new();
+        let mut endpoints = Vec::new();
+        for (i, config) in self.listeners.iter().enumerate() {
+            let name = self.listener_name(i);
+            let incoming = match inherited.take(&name)? {
+                Some(incoming) => {
+                    info!("listener {} uses inherited socket", name);
+                    incoming
+                }
+                None => bind(&format!("listeners[{i}]"), config).await?,
+            };
+            let fd = incoming
+                .as_fd()
+                .try_clone_to_owned()
+                .map_err(|e| io_fail!(e, "duplicate fd of listener {}", name))?;
+            fds.push((name, fd));
+            endpoints.push((config, Box::pin(incoming) as BoxedIncoming));
+        }
+
+        let mut endpoints = endpoints.into_iter();
+        let (primary, incoming) = endpoints
+            .next()
+            .ok_or(ConfigError::new("listeners", "no listener"))?;
+        let policy = policies.next().unwrap();
+        let mut server = ProxyServer::from_incoming(policy.connector().clone(), incoming)
+            .with_policy(policy)
+            .with_timeouts(self.timeouts())
+            .with_limits(self.limits());
+        for protocol in unlisted_protocols(primary) {
+            server = server.without_protocol(protocol);
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &primary.tls {
+            server = server.with_tls(tls_acceptor(tls)?);
+        }
+
+        for ((config, incoming), policy) in endpoints.zip(policies) {
+            let mut listener =
+                Listener::from_incoming(policy.connector().clone(), incoming).with_policy(policy);
+            for protocol in unlisted_protocols(config) {
+                listener = listener.without_protocol(protocol);
+            }
+            #[cfg(feature = "tls")]
+            if let Some(tls) = &config.tls {
+                listener = listener.with_tls(tls_acceptor(tls)?);
+            }
+            server = server.with_listener(listener);
+        }
+
+        if let Some(rewrite) = self.rewrite()? {
+            server = server.with_hook(rewrite);
+        }
+        if let Some(path) = &self.log.access_log {
+            let mut sink = JsonLinesFile::open(path)
+                .map_err(|e| io_fail!(e, "open access log {}", path.display()))?;
+            if let Some(max_bytes) = self.log.access_log_max_bytes {
+                sink = sink.with_rotation(max_bytes, self.log.access_log_keep);
+            }
+            server = server.with_access_log(sink);
+        }
+        Ok(Built { server, fds })
+    }
+}
+
+async fn bind(key: &str, config: &ListenerConfig) -> Result<InheritedListener, ProxyError> {
+    if let Some(addr) = &config.listen {
+        let listener = TcpListener::bind(addr.to_string()).await.map_err(|e| {
+            ConfigError::new(format!("{key}.listen"), format!("bind {addr} fail: {e}"))
+        })?;
+        return Ok(InheritedListener::Tcp(TcpIncoming::from_listener(listener)));
+    }
+    match &config.unix {
+        Some(path) => {
+            let incoming = UnixIncoming::bind(path).map_err(|e| {
+                ConfigError::new(
+                    format!("{key}.unix"),
+                    format!("bind {} fail: {}", path.display(), e),
+                )
+            })?;
+            Ok(InheritedListener::Unix(incoming))
+        }
+        None => Err(ConfigError::new(key, "exactly one of `listen` and `unix` is required").into()),
+    }
+}
+
+/// default protocols missing from `protocols` of the listener
+fn unlisted_protocols(config: &ListenerConfig) -> impl Iterator<Item = &'static str> + '_ {
+    super::DEFAULT_PROTOCOLS
+        .into_iter()
+        .filter(|p| !config.protocols.is_empty() && !config.protocols.iter().any(|c| c == p))
+}
+
+#[cfg(feature = "tls")]
+fn tls_acceptor(config: &super::TlsConfig) -> Result<crate::server::TlsAcceptor, ProxyError> {
+    let acceptor = crate::server::TlsAcceptor::from_pem_files(&config.cert, &config.key)?;
+    match &config.client_ca {
+        Some(ca) => acceptor.with_client_ca(ca),
+        None => Ok(acceptor),
+    }
+}
@@ -1,4 +1,5 @@
 use std::io::Error;
+use std::net::SocketAddr;
 use std::ops::Deref;
 use std::sync::Arc;
 
@@ -8,6 +9,29 @@ use tokio::net::TcpStream;
 use crate::address::Address;
 use crate::transport::{AsyncTransport, BoxedTransport};
 
+mod guard;
+mod router;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(unix)]
+mod unix;
+
+pub use guard::SsrfGuard;
+pub use router::{OutboundHealth, Route, Router};
+#[cfg(feature = "tls")]
+pub use tls::{TlsConnector, TlsConnectorBuilder};
+#[cfg(unix)]
+pub use unix::UnixConnector;
+
+/// how a target was connected, reported by `Connector::connect_tcp_with_info`
+#[derive(Debug, Clone)]
+pub struct Connected {
+    /// outbound that connected the target, e.g. the route picked by `Router`
+    pub outbound: String,
+    /// peer address of the upstream socket, `None` if not a TCP socket
+    pub peer: Option<SocketAddr>,
+}
+
 /// transport connector
 #[async_trait]
 pub trait Connector {
@@ -15,6 +39,24 @@ pub trait Connector {
 
     async fn connect_tcp(&self, addr: &Address) -> Result<Self::Transport, Error>;
 
+    /// connect like `connect_tcp` and report the outbound used
+    async fn connect_tcp_with_info(
+        &self,
+        addr: &Address,
+    ) -> Result<(Self::Transport, Connected), Error> {
+        let transport = self.connect_tcp(addr).await?;
+        let connected = Connected {
+            outbound: self.name().to_string(),
+            peer: None,
+        };
+        Ok((transport, connected))
+    }
+
+    /// connector name, `Connected::outbound` unless `connect_tcp_with_info` reports another
+    fn name(&self) -> &str {
+        "default"
+    }
+
     fn map_transport<M>(self, m: M) -> MapConnector<Self, M>
     where
         Self: Sized,
@@ -43,6 +85,22 @@ impl Connector for DirectConnector {
     async fn connect_tcp(&self, addr: &Address) -> Result<Self::Transport, Error> {
         addr.connect_tcp().await
     }
+
+    async fn connect_tcp_with_info(
+        &self,
+        addr: &Address,
+    ) -> Result<(Self::Transport, Connected), Error> {
+        let stream = addr.connect_tcp().await?;
+        let connected = Connected {
+            outbound: self.name().to_string(),
+            peer: stream.peer_addr().ok(),
+        };
+        Ok((stream, connected))
+    }
+
+    fn name(&self) -> &str {
+        "direct"
+    }
 }
 
 #[async_trait]
@@ -57,6 +115,17 @@ where
     async fn connect_tcp(&self, addr: &Address) -> Result<Self::Transport, Error> {
         self.deref().connect_tcp(addr).await
     }
+
+    async fn connect_tcp_with_info(
+        &self,
+        addr: &Address,
+    ) -> Result<(Self::Transport, Connected), Error> {
+        self.deref().connect_tcp_with_info(addr).await
+    }
+
+    fn name(&self) -> &str {
+        self.deref().name()
+    }
 }
 
 pub struct MapConnector<C, M> {
@@ -83,4 +152,16 @@ where
         let transport = self.connector.connect_tcp(addr).await?;
         Ok((self.map)(transport))
     }
+
+    async fn connect_tcp_with_info(
+        &self,
+        addr: &Address,
+    ) -> Result<(Self::Transport, Connected), Error> {
+        let (transport, connected) = self.connector.connect_tcp_with_info(addr).await?;
+        Ok(((self.map)(transport), connected))
+    }
+
+    fn name(&self) -> &str {
+        self.connector.name()
+    }
 }
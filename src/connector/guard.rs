@@ -0,0 +1,223 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use async_trait::async_trait;
+use tokio::net::lookup_host;
+
+use crate::address::Address;
+use crate::connector::{Connected, Connector};
+use crate::util::Cidr;
+
+/// loopback, link-local, RFC 1918, CGNAT, documentation, multicast and other
+/// special-purpose ranges, NAT64 addresses are checked by their embedded ipv4
+const SPECIAL_PURPOSE: [&str; 23] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "100::/64",
+    "2001::/32",
+    "2001:db8::/32",
+    "2002::/16",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// resolve targets before connecting and refuse blocked ips, the inner connector
+/// only sees the vetted `Address::Sock`, so a name can't be rebound between the
+/// check and the connect
+///
+/// refusals fail with `ErrorKind::PermissionDenied`
+pub struct SsrfGuard<C> {
+    connector: C,
+    blocked: Vec<Cidr>,
+    allowed: Vec<Cidr>,
+}
+
+impl<C> SsrfGuard<C> {
+    /// block the special-purpose ranges
+    pub fn new(connector: C) -> Self {
+        let blocked = SPECIAL_PURPOSE
+            .iter()
+            .map(|s| s.parse().expect("valid special-purpose cidr"))
+            .collect();
+        Self {
+            connector,
+            blocked,
+            allowed: Vec::new(),
+        }
+    }
+
+    pub fn with_blocked(mut self, cidr: Cidr) -> Self {
+        self.blocked.push(cidr);
+        self
+    }
+
+    /// exception to the blocked ranges
+    pub fn with_allowed(mut self, cidr: Cidr) -> Self {
+        self.allowed.push(cidr);
+        self
+    }
+
+    /// an ipv6 address embedding an ipv4 one is also blocked by the ipv4 ranges
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        let allowed = |ip: IpAddr| self.allowed.iter().any(|c| c.contains(ip));
+        let blocked = |ip: IpAddr| !allowed(ip) && self.blocked.iter().any(|c| c.contains(ip));
+        blocked(ip) || embedded_ipv4(ip).is_some_and(|v4| blocked(IpAddr::V4(v4)))
+    }
+
+    async fn resolve(&self, addr: &Address) -> Result<Vec<SocketAddr>, Error> {
+        let resolved: Vec<SocketAddr> = match addr {
+            Address::Sock(addr) => vec![*addr],
+            Address::Domain(host, port) => lookup_host((host.as_str(), *port)).await?.collect(),
+        };
+        let vetted: Vec<SocketAddr> = resolved
+            .iter()
+            .filter(|a| !self.is_blocked(a.ip()))
+            .copied()
+            .collect();
+        if vetted.is_empty() {
+            let ips: Vec<String> = resolved.iter().map(|a| a.ip().to_string()).collect();
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} resolves to blocked address [{}]", addr, ips.join(", ")),
+            ));
+        }
+        Ok(vetted)
+    }
+}
+
+/// ipv4 inside an ipv4-mapped, ipv4-compatible, NAT64, Teredo or 6to4 ipv6 address
+fn embedded_ipv4(ip: IpAddr) -> Option<Ipv4Addr> {
+    let IpAddr::V6(v6) = ip else {
+        return None;
+    };
+    if let Some(v4) = v6.to_ipv4() {
+        return Some(v4);
+    }
+    let bits = u128::from(v6);
+    if bits >> 32 == 0x0064_ff9b_0000_0000_0000_0000 {
+        // 64:ff9b::aabb:ccdd carries aa.bb.cc.dd in the last 32 bits
+        return Some(Ipv4Addr::from(bits as u32));
+    }
+    if bits >> 96 == 0x2001_0000 {
+        // Teredo carries the client ipv4 inverted in the last 32 bits
+        return Some(Ipv4Addr::from(!(bits as u32)));
+    }
+    // 2002:aabb:ccdd::/48 carries aa.bb.cc.dd in bits 16..48
+    (bits >> 112 == 0x2002).then(|| Ipv4Addr::from((bits >> 80) as u32))
+}
+
+#[async_trait]
+impl<C> Connector for SsrfGuard<C>
+where
+    C: Connector + Sync,
+    C::Transport: Send,
+{
+    type Transport = C::Transport;
+
+    async fn connect_tcp(&self, addr: &Address) -> Result<Self::Transport, Error> {
+        let (transport, _) = self.connect_tcp_with_info(addr).await?;
+        Ok(transport)
+    }
+
+    async fn connect_tcp_with_info(
+        &self,
+        addr: &Address,
+    ) -> Result<(Self::Transport, Connected), Error> {
+        let mut last_err = None;
+        for vetted in self.resolve(addr).await? {
+            let vetted = Address::Sock(vetted);
+            match self.connector.connect_tcp_with_info(&vetted).await {
+                Ok(connected) => return Ok(connected),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::from(ErrorKind::AddrNotAvailable)))
+    }
+
+    fn name(&self) -> &str {
+        self.connector.name()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::SsrfGuard;
+    use crate::address::Address;
+    use crate::connector::{Connector, DirectConnector};
+    use crate::server::ProxyServer;
+
+    #[tokio::test]
+    async fn test_block_resolved() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let guard = SsrfGuard::new(DirectConnector);
+        assert!(guard.is_blocked("169.254.169.254".parse().unwrap()));
+        assert!(guard.is_blocked("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(guard.is_blocked("::ffff:7f00:1".parse().unwrap()));
+        assert!(guard.is_blocked("::127.0.0.1".parse().unwrap()));
+        assert!(guard.is_blocked("2002:a9fe:a9fe::1".parse().unwrap()));
+        assert!(guard.is_blocked("198.51.100.7".parse().unwrap()));
+        assert!(!guard.is_blocked("93.184.216.34".parse().unwrap()));
+        assert!(!guard.is_blocked("::ffff:93.184.216.34".parse().unwrap()));
+        assert!(!guard.is_blocked("2606:2800:220:1::".parse().unwrap()));
+        assert!(guard.is_blocked("2001:db8::1".parse().unwrap()));
+        assert!(guard.is_blocked("100::1".parse().unwrap()));
+        assert!(guard.is_blocked("2001:0:4136:e378:8000:63bf:a247:27dd".parse().unwrap()));
+        // NAT64 is blocked by the embedded ip only
+        assert!(guard.is_blocked("64:ff9b::a9fe:a9fe".parse().unwrap()));
+        assert!(guard.is_blocked("64:ff9b::7f00:1".parse().unwrap()));
+        assert!(!guard.is_blocked("64:ff9b::5db8:d822".parse().unwrap()));
+
+        let addr = Address::Domain("localhost".to_string(), port);
+        let e = guard.connect_tcp(&addr).await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+
+        let guard = guard.with_allowed("127.0.0.1".parse().unwrap());
+        assert!(!guard.is_blocked("::ffff:127.0.0.1".parse().unwrap()));
+        // the embedded ip is blocked on its own, only the 6to4 prefix is allowed
+        let sixtofour = SsrfGuard::new(DirectConnector).with_allowed("2002::/16".parse().unwrap());
+        assert!(!sixtofour.is_blocked("2002:5db8:d822::1".parse().unwrap()));
+        assert!(sixtofour.is_blocked("2002:a00:1::1".parse().unwrap()));
+        let teredo = SsrfGuard::new(DirectConnector).with_allowed("2001::/32".parse().unwrap());
+        assert!(!teredo.is_blocked("2001:0:4136:e378:8000:63bf:a247:27dd".parse().unwrap()));
+        assert!(teredo.is_blocked("2001:0:4136:e378:8000:63bf:3fff:fdd2".parse().unwrap()));
+        let addr = Address::Sock(target.local_addr().unwrap());
+        assert!(guard.connect_tcp(&addr).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refusal() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = ProxyServer::from_listener(SsrfGuard::new(DirectConnector), listener);
+        tokio::spawn(server.run());
+
+        let mut sock = TcpStream::connect(proxy).await.unwrap();
+        sock.write_all(b"\x05\x01\x00\x05\x01\x00\x03\x09localhost\x00\x50")
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        sock.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(&reply[..4], b"\x05\x00\x05\x02");
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::ProxyError;
+use crate::address::Address;
+use crate::connector::{ArcConnector, Connected, Connector};
+use crate::server::{DomainPattern, matches_target};
+use crate::transport::BoxedTransport;
+use crate::util::Cidr;
+
+/// send matching targets to an outbound, matches when every non-empty condition matches
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub outbound: String,
+    /// destination domains, IP targets never match
+    pub domains: Vec<DomainPattern>,
+    /// destination ips, domain targets never match
+    pub ips: Vec<Cidr>,
+    pub ports: Vec<RangeInclusive<u16>>,
+}
+
+impl Route {
+    pub fn new(outbound: &str) -> Self {
+        Self {
+            outbound: outbound.to_string(),
+            domains: Vec::new(),
+            ips: Vec::new(),
+            ports: Vec::new(),
+        }
+    }
+
+    pub fn with_domain(mut self, pattern: &str) -> Self {
+        self.domains.push(DomainPattern::new(pattern));
+        self
+    }
+
+    pub fn with_ip(mut self, cidr: Cidr) -> Self {
+        self.ips.push(cidr);
+        self
+    }
+
+    pub fn with_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports.push(ports);
+        self
+    }
+
+    pub fn matches(&self, target: &Address) -> bool {
+        matches_target(&self.domains, &self.ips, &self.ports, target)
+    }
+}
+
+/// connect results of an outbound since the router was built, refused targets
+/// and connects abandoned by the connect timeout are not counted
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OutboundHealth {
+    pub name: String,
+    pub connects: u64,
+    pub failures: u64,
+    /// failures since the last successful connect
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+    /// latency of the last successful connect
+    pub last_latency_ms: Option<u64>,
+}
+
+struct Outbound {
+    connector: ArcConnector,
+    health: Mutex<OutboundHealth>,
+}
+
+impl Outbound {
+    fn new(name: &str, connector: ArcConnector) -> Self {
+        let health = OutboundHealth {
+            name: name.to_string(),
+            ..OutboundHealth::default()
+        };
+        Self {
+            connector,
+            health: Mutex::new(health),
+        }
+    }
+
+    fn record(&self, start: Instant, result: Result<(), &Error>) {
+        let mut health = self.health.lock().unwrap();
+        match result {
+            Ok(_) => {
+                health.connects += 1;
+                health.consecutive_failures = 0;
+                health.last_latency_ms = Some(start.elapsed().as_millis() as u64);
+            }
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {}
+            Err(e) => {
+                health.connects += 1;
+                health.failures += 1;
+                health.consecutive_failures += 1;
+                health.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+/// named outbounds selected by ordered routes, the first matching route decides,
+/// unmatched targets use the default outbound
+pub struct Router {
+    outbounds: HashMap<String, Outbound>,
+    routes: Vec<Route>,
+    default: String,
+}
+
+impl Router {
+    pub fn new(default: &str, connector: ArcConnector) -> Self {
+        Self {
+            outbounds: HashMap::from([(default.to_string(), Outbound::new(default, connector))]),
+            routes: Vec::new(),
+            default: default.to_string(),
+        }
+    }
+
+    pub fn with_outbound(mut self, name: &str, connector: ArcConnector) -> Self {
+        self.outbounds
+            .insert(name.to_string(), Outbound::new(name, connector));
+        self
+    }
+
+    /// fails if the outbound of `route` is not registered
+    pub fn with_route(mut self, route: Route) -> Result<Self, ProxyError> {
+        if !self.outbounds.contains_key(&route.outbound) {
+            return Err(invalid_data!("unknown outbound {:?}", route.outbound));
+        }
+        self.routes.push(route);
+        Ok(self)
+    }
+
+    /// name of the outbound for `target`
+    pub fn route(&self, target: &Address) -> &str {
+        self.routes
+            .iter()
+            .find(|r| r.matches(target))
+            .map(|r| r.outbound.as_str())
+            .unwrap_or(&self.default)
+    }
+
+    pub fn outbound_names(&self) -> impl Iterator<Item = &str> {
+        self.outbounds.keys().map(String::as_str)
+    }
+
+    /// health of each outbound ordered by name
+    pub fn health(&self) -> Vec<OutboundHealth> {
+        let mut health: Vec<_> = self
+            .outbounds
+            .values()
+            .map(|o| o.health.lock().unwrap().clone())
+            .collect();
+        health.sort_by(|a, b| a.name.cmp(&b.name));
+        health
+    }
+}
+
+#[async_trait]
+impl Connector for Router {
+    type Transport = BoxedTransport;
+
+    async fn connect_tcp(&self, addr: &Address) -> Result<Self::Transport, Error> {
+        let (transport, _) = self.connect_tcp_with_info(addr).await?;
+        Ok(transport)
+    }
+
+    /// reports the name of the route picked for `addr`
+    async fn connect_tcp_with_info(
+        &self,
+        addr: &Address,
+    ) -> Result<(Self::Transport, Connected), Error> {
+        let name = self.route(addr);
+        debug!("route {} to outbound {}", addr, name);
+        let outbound = &self.outbounds[name];
+        let start = Instant::now();
+        let result = outbound.connector.connect_tcp_with_info(addr).await;
+        outbound.record(start, result.as_ref().map(|_| ()));
+        let (transport, connected) = result?;
+        let connected = Connected {
+            outbound: name.to_string(),
+            ..connected
+        };
+        Ok((transport, connected))
+    }
+
+    fn name(&self) -> &str {
+        "router"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Error, ErrorKind};
+
+    use async_trait::async_trait;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{Route, Router};
+    use crate::address::Address;
+    use crate::connector::{Connector, DirectConnector};
+
+    struct Refuse;
+
+    #[async_trait]
+    impl Connector for Refuse {
+        type Transport = TcpStream;
+
+        async fn connect_tcp(&self, _: &Address) -> Result<Self::Transport, Error> {
+            Err(Error::from(ErrorKind::PermissionDenied))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (sock, _) = target.accept().await.unwrap();
+            drop(sock);
+        });
+
+        let missing =
+            Router::new("direct", DirectConnector.make_arc()).with_route(Route::new("missing"));
+        assert!(missing.is_err());
+
+        let router = Router::new("direct", DirectConnector.make_arc())
+            .with_outbound("blackhole", Refuse.make_arc())
+            .with_route(Route::new("blackhole").with_domain("*.ads.test"))
+            .unwrap()
+            .with_route(Route::new("blackhole").with_ports(25..=25))
+            .unwrap();
+        let route = |s: &str| router.route(&s.parse().unwrap()).to_string();
+        assert_eq!(route("cdn.ads.test:443"), "blackhole");
+        assert_eq!(route("example.com:443"), "direct");
+        assert_eq!(route("10.0.0.1:25"), "blackhole");
+
+        let e = router
+            .connect_tcp(&"cdn.ads.test:443".parse().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        let (mut remote, connected) = router
+            .connect_tcp_with_info(&Address::Sock(target_addr))
+            .await
+            .unwrap();
+        assert_eq!(connected.outbound, "direct");
+        let mut buf = Vec::new();
+        remote.read_to_end(&mut buf).await.unwrap();
+        let e = router
+            .connect_tcp(&Address::Sock(target_addr))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+
+        let health = router.health();
+        assert_eq!(health[0].name, "blackhole");
+        assert_eq!(health[0].connects, 0);
+        assert_eq!(health[1].name, "direct");
+        assert_eq!((health[1].connects, health[1].failures), (2, 1));
+        assert_eq!(health[1].consecutive_failures, 1);
+        assert!(health[1].last_error.is_some());
+        assert!(health[1].last_latency_ms.is_some());
+    }
+}
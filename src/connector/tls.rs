@@ -0,0 +1,226 @@
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::client::TlsStream;
+
+use crate::ProxyError;
+use crate::address::Address;
+use crate::connector::{Connected, Connector};
+use crate::util::tls::{load_certs, load_private_key, provider};
+
+/// upgrade transports from the inner connector to TLS
+pub struct TlsConnector<C> {
+    connector: C,
+    inner: tokio_rustls::TlsConnector,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl<C> TlsConnector<C> {
+    pub fn new(connector: C, config: Arc<ClientConfig>) -> Self {
+        Self {
+            connector,
+            inner: config.into(),
+            server_name: None,
+        }
+    }
+
+    /// use a fixed server name instead of the host of the connecting address
+    pub fn with_server_name(mut self, server_name: ServerName<'static>) -> Self {
+        self.server_name = Some(server_name);
+        self
+    }
+
+    fn server_name(&self, addr: &Address) -> Result<ServerName<'static>, Error> {
+        if let Some(name) = &self.server_name {
+            return Ok(name.clone());
+        }
+        match addr {
+            Address::Domain(host, _) => ServerName::try_from(host.clone())
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid sni: {host}"))),
+            Address::Sock(addr) => Ok(ServerName::IpAddress(addr.ip().into())),
+        }
+    }
+}
+
+#[async_trait]
+impl<C> Connector for TlsConnector<C>
+where
+    C: Connector + Sync,
+    C::Transport: Unpin + Send,
+{
+    type Transport = TlsStream<C::Transport>;
+
+    async fn connect_tcp(&self, addr: &Address) -> Result<Self::Transport, Error> {
+        let server_name = self.server_name(addr)?;
+        let transport = self.connector.connect_tcp(addr).await?;
+        self.inner.connect(server_name, transport).await
+    }
+
+    async fn connect_tcp_with_info(
+        &self,
+        addr: &Address,
+    ) -> Result<(Self::Transport, Connected), Error> {
+        let server_name = self.server_name(addr)?;
+        let (transport, connected) = self.connector.connect_tcp_with_info(addr).await?;
+        let transport = self.inner.connect(server_name, transport).await?;
+        // keep the outbound picked by the inner connector, e.g. a `Router` route
+        let connected = Connected {
+            outbound: format!("{}+{}", connected.outbound, self.name()),
+            ..connected
+        };
+        Ok((transport, connected))
+    }
+
+    fn name(&self) -> &str {
+        "tls"
+    }
+}
+
+/// rustls client config builder for `TlsConnector`, the webpki roots are
+/// trusted unless replaced with `root_store`
+pub struct TlsConnectorBuilder {
+    roots: RootCertStore,
+    alpn_protocols: Vec<Vec<u8>>,
+    client_auth: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    server_name: Option<ServerName<'static>>,
+    disable_sni: bool,
+}
+
+impl Default for TlsConnectorBuilder {
+    fn default() -> Self {
+        Self {
+            roots: RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+            alpn_protocols: Vec::new(),
+            client_auth: None,
+            server_name: None,
+            disable_sni: false,
+        }
+    }
+}
+
+impl TlsConnectorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// trust the certificates in PEM file
+    pub fn add_root_pem_file<P: AsRef<Path>>(mut self, path: P) -> Result<Self, ProxyError> {
+        for cert in load_certs(path.as_ref())? {
+            self.roots
+                .add(cert)
+                .map_err(|e| format_err!("add root {} fail: {}", path.as_ref().display(), e))?;
+        }
+        Ok(self)
+    }
+
+    pub fn root_store(mut self, roots: RootCertStore) -> Self {
+        self.roots = roots;
+        self
+    }
+
+    pub fn alpn_protocols<P: Into<Vec<u8>>>(
+        mut self,
+        protocols: impl IntoIterator<Item = P>,
+    ) -> Self {
+        self.alpn_protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// present the client certificate chain and key from PEM files
+    pub fn client_auth_pem_files<P, K>(
+        mut self,
+        cert_path: P,
+        key_path: K,
+    ) -> Result<Self, ProxyError>
+    where
+        P: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let certs = load_certs(cert_path.as_ref())?;
+        let key = load_private_key(key_path.as_ref())?;
+        self.client_auth = Some((certs, key));
+        Ok(self)
+    }
+
+    /// fixed SNI, by default the host of the connecting address is used
+    pub fn server_name(mut self, name: &str) -> Result<Self, ProxyError> {
+        let name = ServerName::try_from(name.to_string())
+            .map_err(|_| invalid_data!("invalid server name: {}", name))?;
+        self.server_name = Some(name);
+        Ok(self)
+    }
+
+    pub fn disable_sni(mut self) -> Self {
+        self.disable_sni = true;
+        self
+    }
+
+    pub fn build<C>(self, connector: C) -> Result<TlsConnector<C>, ProxyError> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format_err!("build tls config fail: {}", e))?
+            .with_root_certificates(self.roots);
+        let mut config = match self.client_auth {
+            Some((certs, key)) => builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| format_err!("invalid client certificate: {}", e))?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn_protocols;
+        config.enable_sni = !self.disable_sni;
+        let connector = TlsConnector::new(connector, Arc::new(config));
+        Ok(match self.server_name {
+            Some(name) => connector.with_server_name(name),
+            None => connector,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::TlsConnectorBuilder;
+    use crate::connector::{Connector, DirectConnector};
+    use crate::server::TlsAcceptor;
+    use crate::testing::{TempDir, self_signed};
+
+    #[tokio::test]
+    async fn test_connect_with_custom_root() {
+        let dir = TempDir::new("tls-client");
+        let (pem, _) = self_signed(&dir, "cert", &["proxy.test"]);
+
+        let acceptor = TlsAcceptor::from_pem_files(&pem.cert, &pem.key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut sock = acceptor.accept(sock).await.unwrap();
+            sock.write_all(b"pong").await.unwrap();
+            sock.shutdown().await.unwrap();
+        });
+
+        assert!(!TlsConnectorBuilder::new().roots.is_empty());
+        let connector = TlsConnectorBuilder::new()
+            .root_store(rustls::RootCertStore::empty())
+            .add_root_pem_file(&pem.cert)
+            .unwrap()
+            .server_name("proxy.test")
+            .unwrap()
+            .build(DirectConnector)
+            .unwrap();
+        let (mut transport, connected) =
+            connector.connect_tcp_with_info(&addr.into()).await.unwrap();
+        assert_eq!(connected.outbound, "direct+tls");
+        let mut buf = Vec::new();
+        transport.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pong");
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::net::UnixStream;
+
+use crate::address::Address;
+use crate::connector::Connector;
+
+/// host suffix resolved in the socket directory, `name.unix` connects to `<dir>/name.sock`
+const UNIX_HOST_SUFFIX: &str = ".unix";
+
+/// connect to unix sockets mapped from target addresses, unmapped targets are refused
+///
+/// a target is looked up by `host:port` first, then by host, then `name.unix`
+/// hosts are resolved in the socket directory
+#[derive(Debug, Clone, Default)]
+pub struct UnixConnector {
+    routes: HashMap<String, PathBuf>,
+    hosts: HashMap<String, PathBuf>,
+    socket_dir: Option<PathBuf>,
+}
+
+impl UnixConnector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// map a target `host:port` to `path`
+    pub fn with_route<P: AsRef<Path>>(mut self, addr: &Address, path: P) -> Self {
+        self.routes
+            .insert(addr.to_string(), path.as_ref().to_path_buf());
+        self
+    }
+
+    /// map `host` on any port to `path`
+    pub fn with_host<P: AsRef<Path>>(mut self, host: &str, path: P) -> Self {
+        self.hosts
+            .insert(host.to_ascii_lowercase(), path.as_ref().to_path_buf());
+        self
+    }
+
+    /// resolve `name.unix` hosts to `<dir>/name.sock`
+    pub fn with_socket_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.socket_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn socket_path(&self, addr: &Address) -> Option<PathBuf> {
+        if let Some(path) = self.routes.get(&addr.to_string()) {
+            return Some(path.clone());
+        }
+        let host = addr.host().to_ascii_lowercase();
+        if let Some(path) = self.hosts.get(&host) {
+            return Some(path.clone());
+        }
+        let name = host.strip_suffix(UNIX_HOST_SUFFIX)?;
+        // the name must stay inside the socket directory
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return None;
+        }
+        let dir = self.socket_dir.as_ref()?;
+        Some(dir.join(format!("{name}.sock")))
+    }
+}
+
+#[async_trait]
+impl Connector for UnixConnector {
+    type Transport = UnixStream;
+
+    async fn connect_tcp(&self, addr: &Address) -> Result<Self::Transport, Error> {
+        match self.socket_path(addr) {
+            Some(path) => UnixStream::connect(&path).await.map_err(|e| {
+                Error::new(e.kind(), format!("connect {} fail: {}", path.display(), e))
+            }),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no unix socket for {addr}"),
+            )),
+        }
+    }
+
+    fn name(&self) -> &str {
+        "unix"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    use super::UnixConnector;
+    use crate::address::Address;
+    use crate::server::{ProxyServer, UnixIncoming};
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn test_unix_listener_and_connector() {
+        let dir = TempDir::new("unix");
+
+        let sidecar = UnixListener::bind(dir.join("sidecar.sock")).unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = sidecar.accept().await.unwrap();
+            sock.write_all(b"pong").await.unwrap();
+        });
+        let connector = UnixConnector::new().with_socket_dir(dir.path());
+        let addr = Address::Domain("sidecar.unix".to_string(), 80);
+        assert_eq!(connector.socket_path(&addr), Some(dir.join("sidecar.sock")));
+        let escape = Address::Domain("..unix".to_string(), 80);
+        assert_eq!(connector.socket_path(&escape), None);
+
+        let proxy_path = dir.join("proxy.sock");
+        let incoming = UnixIncoming::bind(&proxy_path).unwrap();
+        let server = ProxyServer::from_incoming(connector, incoming);
+        tokio::spawn(server.run());
+
+        let mut sock = tokio::net::UnixStream::connect(&proxy_path).await.unwrap();
+        sock.write_all(b"CONNECT sidecar.unix:80 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut data = Vec::new();
+        sock.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"HTTP/1.1 200 Ok\r\n\r\npong");
+
+        // binding again replaces the stale socket file
+        drop(UnixIncoming::bind(&proxy_path).unwrap());
+    }
+}
@@ -14,10 +14,41 @@ pub enum ProxyError {
     ProtocolFail(String),
     #[error("connect remote({0}) fail: {1}")]
     ConnectRemoteFail(Address, String),
+    #[error("handshake timeout")]
+    HandshakeTimeout,
+    #[error("connect remote({0}) timeout")]
+    ConnectTimeout(Address),
+    #[error("idle timeout")]
+    IdleTimeout,
+    #[error("connect remote({0}) denied")]
+    Denied(Address),
+    #[error("authentication fail")]
+    Unauthorized,
+    #[error("killed")]
+    Killed,
     #[error("{0}")]
     Other(String),
 }
 
+impl ProxyError {
+    /// variant name, used as metrics label
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "io",
+            Self::InvalidData(_) => "invalid_data",
+            Self::ProtocolFail(_) => "protocol_fail",
+            Self::ConnectRemoteFail(..) => "connect_remote_fail",
+            Self::HandshakeTimeout => "handshake_timeout",
+            Self::ConnectTimeout(_) => "connect_timeout",
+            Self::IdleTimeout => "idle_timeout",
+            Self::Denied(_) => "denied",
+            Self::Unauthorized => "unauthorized",
+            Self::Killed => "killed",
+            Self::Other(_) => "other",
+        }
+    }
+}
+
 impl From<io::Error> for ProxyError {
     fn from(e: io::Error) -> Self {
         Self::Io(e)
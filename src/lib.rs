@@ -7,11 +7,16 @@ extern crate tracing;
 mod error;
 
 mod address;
+#[cfg(feature = "config")]
+pub mod config;
 pub mod connector;
 pub mod server;
 pub mod transport;
 pub mod util;
 
+#[cfg(test)]
+mod testing;
+
 pub use self::address::Address;
 pub use self::connector::Connector;
 pub use self::error::ProxyError;
@@ -0,0 +1,338 @@
+//! `proxies` binary, serves the listeners of a TOML or YAML config file
+//!
+//! ```text
+//! proxies [-c|--config <path>] [--check]
+//! ```
+
+#[cfg(unix)]
+#[macro_use]
+extern crate tracing;
+
+use std::path::PathBuf;
+
+use proxies::ProxyError;
+use proxies::config::Config;
+
+/// config file read without `--config`
+const DEFAULT_CONFIG: &str = "proxies.toml";
+
+const USAGE: &str = "usage: proxies [-c|--config <path>] [--check]";
+
+struct Args {
+    config: String,
+    /// validate the config and exit
+    check: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            config: DEFAULT_CONFIG.to_string(),
+            check: false,
+        };
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-c" | "--config" => match iter.next() {
+                    Some(path) => args.config = path,
+                    None => return Err(format!("{arg} requires a path")),
+                },
+                "--check" => args.check = true,
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                other => return Err(format!("unknown argument {other:?}")),
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn main() {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let config = match Config::load(&args.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}: {}", args.config, e);
+            std::process::exit(1);
+        }
+    };
+    if args.check {
+        println!("{}: ok", args.config);
+        return;
+    }
+    if let Err(e) = run(PathBuf::from(args.config), config) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(unix))]
+fn run(_path: PathBuf, _config: Config) -> Result<(), ProxyError> {
+    Err(ProxyError::InvalidData(
+        "serving is only supported on unix".to_string(),
+    ))
+}
+
+#[cfg(unix)]
+fn run(path: PathBuf, config: Config) -> Result<(), ProxyError> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| ProxyError::Other(format!("start runtime fail: {e}")))?;
+    runtime.block_on(service::serve(path, config))
+}
+
+#[cfg(unix)]
+mod service {
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, RwLock};
+    use std::time::{Duration, SystemTime};
+
+    use tokio::net::TcpListener;
+    use tokio::signal::unix::{SignalKind, signal};
+    use tokio::time::{Interval, MissedTickBehavior};
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::prelude::*;
+    use tracing_subscriber::{Registry, fmt, reload};
+
+    use proxies::ProxyError;
+    use proxies::config::{Config, ConfigError};
+    use proxies::connector::Router;
+    use proxies::server::{Admin, Handoff, HandoffReceiver, ListenFds, Metrics, ReloadHandle};
+
+    /// grace period without `shutdown_grace`
+    const DEFAULT_GRACE: Duration = Duration::from_secs(30);
+
+    type LogHandle = reload::Handle<LevelFilter, Registry>;
+
+    fn init_log(config: &Config) -> LogHandle {
+        let level = config.log.level.parse().unwrap_or(LevelFilter::INFO);
+        let (filter, handle) = reload::Layer::new(level);
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(fmt::layer())
+            .init();
+        handle
+    }
+
+    pub(crate) async fn serve(path: PathBuf, config: Config) -> Result<(), ProxyError> {
+        let log = init_log(&config);
+
+        // take over the listeners of a running server, then of systemd
+        let mut receiver = match &config.handoff {
+            Some(path) if path.exists() => match HandoffReceiver::connect(path).await {
+                Ok(receiver) => Some(receiver),
+                Err(e) => {
+                    warn!("{}, binding listeners", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let mut env_fds;
+        let inherited = match &mut receiver {
+            Some(receiver) => receiver.listeners(),
+            None => {
+                env_fds = ListenFds::from_env()?;
+                &mut env_fds
+            }
+        };
+        let built = config.build_server(inherited).await?;
+
+        let metrics = Metrics::new();
+        let server = built.server.with_metrics(metrics.clone());
+        if let Some(metrics_config) = &config.metrics {
+            let addr = &metrics_config.listen;
+            let listener = TcpListener::bind(addr.to_string())
+                .await
+                .map_err(|e| ProxyError::Other(format!("bind metrics {addr} fail: {e}")))?;
+            tokio::spawn(async move {
+                if let Err(e) = metrics.serve(listener).await {
+                    error!("metrics: {}", e);
+                }
+            });
+        }
+
+        let shutdown = server.shutdown_handle();
+        let grace = config.shutdown_grace.unwrap_or(DEFAULT_GRACE);
+        if let Some(path) = &config.handoff {
+            let mut handoff = Handoff::new(shutdown.clone(), grace);
+            for (name, fd) in &built.fds {
+                handoff = handoff.with_listener(name, fd)?;
+            }
+            let path = path.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handoff.serve(path).await {
+                    error!("handoff: {}", e);
+                }
+            });
+        }
+        if let Some(receiver) = receiver {
+            receiver.complete().await?;
+        }
+
+        let mut terminate = signal(SignalKind::terminate())
+            .map_err(|e| ProxyError::Other(format!("listen for SIGTERM fail: {e}")))?;
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            info!("shutting down, grace {:?}", grace);
+            shutdown.shutdown(grace);
+        });
+        let admin_config = config.admin.clone();
+        let reloader = Reloader {
+            path,
+            config: Arc::new(RwLock::new(config)),
+            server: server.reload_handle(),
+            log,
+        };
+        if let Some(admin_config) = admin_config {
+            let addr = &admin_config.listen;
+            let listener = TcpListener::bind(addr.to_string())
+                .await
+                .map_err(|e| ProxyError::Other(format!("bind admin {addr} fail: {e}")))?;
+            let mut admin = reloader.admin(Admin::new(server.registry()));
+            if let Some(token) = &admin_config.token {
+                admin = admin.with_token(token);
+            }
+            tokio::spawn(async move {
+                if let Err(e) = admin.serve(listener).await {
+                    error!("admin: {}", e);
+                }
+            });
+        }
+        tokio::spawn(reloader.run()?);
+
+        let summary = server.run().await?;
+        info!(
+            "stopped, {} sessions drained, {} aborted in {:?}",
+            summary.drained, summary.aborted, summary.elapsed
+        );
+        Ok(())
+    }
+
+    /// reload the config file on SIGHUP and when it changes, invalid configs
+    /// are rejected and the running config is kept
+    struct Reloader {
+        path: PathBuf,
+        /// running config, shown by the admin API
+        config: Arc<RwLock<Config>>,
+        server: ReloadHandle<Arc<Router>>,
+        log: LogHandle,
+    }
+
+    impl Reloader {
+        /// serve the running config, outbound health and log level
+        fn admin(&self, admin: Admin) -> Admin {
+            let config = self.config.clone();
+            let server = self.server.clone();
+            let (get, set) = (self.log.clone(), self.log.clone());
+            admin
+                .with_config(move || {
+                    serde_json::to_value(config.read().unwrap().redacted()).unwrap_or_default()
+                })
+                .with_outbounds(move || {
+                    server
+                        .policy(0)
+                        .map(|p| p.connector().health())
+                        .unwrap_or_default()
+                })
+                .with_log_level(
+                    move || get.clone_current().unwrap_or(LevelFilter::OFF).to_string(),
+                    move |level| {
+                        let level: LevelFilter = level.parse().map_err(|e| format!("{e}"))?;
+                        set.modify(|filter| *filter = level)
+                            .map_err(|e| e.to_string())?;
+                        info!("log level set to {}", level);
+                        Ok(())
+                    },
+                )
+        }
+
+        fn run(self) -> Result<impl Future<Output = ()>, ProxyError> {
+            let mut hangup = signal(SignalKind::hangup())
+                .map_err(|e| ProxyError::Other(format!("listen for SIGHUP fail: {e}")))?;
+            let mut watch = self.config.read().unwrap().watch.map(|period| {
+                let mut interval = tokio::time::interval(period);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                interval
+            });
+            Ok(async move {
+                let mut last_modified = modified(&self.path);
+                loop {
+                    tokio::select! {
+                        Some(()) = hangup.recv() => info!("SIGHUP, reload {}", self.path.display()),
+                        _ = tick(&mut watch) => {
+                            let now = modified(&self.path);
+                            if now == last_modified {
+                                continue;
+                            }
+                            last_modified = now;
+                            info!("{} changed, reload", self.path.display());
+                        }
+                    }
+                    match self.reload() {
+                        Ok(()) => info!("reload {} done", self.path.display()),
+                        Err(e) => error!(
+                            "reload {} fail, keep the running config: {}",
+                            self.path.display(),
+                            e
+                        ),
+                    }
+                }
+            })
+        }
+
+        fn reload(&self) -> Result<(), ProxyError> {
+            let config = Config::load(&self.path)?;
+            let mut running = self.config.write().unwrap();
+            let names = |c: &Config| {
+                (0..c.listeners.len())
+                    .map(|i| c.listener_name(i))
+                    .collect::<Vec<_>>()
+            };
+            if names(&config) != names(&running) {
+                return Err(ConfigError::new(
+                    "listeners",
+                    "listeners added or removed, restart to apply",
+                )
+                .into());
+            }
+            self.server.reload(config.policies()?)?;
+            if config.log.level != running.log.level {
+                let level = config.log.level.parse().unwrap_or(LevelFilter::INFO);
+                if let Err(e) = self.log.modify(|filter| *filter = level) {
+                    warn!("set log level fail: {}", e);
+                }
+            }
+            for key in running.restart_keys(&config) {
+                warn!("{} changed, restart to apply", key);
+            }
+            *running = config;
+            Ok(())
+        }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    async fn tick(watch: &mut Option<Interval>) {
+        match watch {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::ProxyError;
+
+/// class of an error returned by the incoming stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptErrorKind {
+    /// error of a single connection, accept again immediately
+    Transient,
+    /// out of file descriptors or memory, accept again after backoff
+    ResourceExhausted,
+    /// the listener is broken, stop the server
+    Fatal,
+}
+
+impl AcceptErrorKind {
+    pub fn classify(e: &Error) -> Self {
+        match e.kind() {
+            ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut => return Self::Transient,
+            ErrorKind::OutOfMemory => return Self::ResourceExhausted,
+            _ => {}
+        }
+        #[cfg(unix)]
+        match e.raw_os_error() {
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) => {
+                return Self::ResourceExhausted;
+            }
+            // network errors pending on the new socket, see accept(2)
+            Some(
+                libc::EPROTO
+                | libc::EPERM
+                | libc::ENETDOWN
+                | libc::ENETUNREACH
+                | libc::ENOPROTOOPT
+                | libc::EHOSTDOWN
+                | libc::EHOSTUNREACH
+                | libc::EOPNOTSUPP,
+            ) => return Self::Transient,
+            _ => {}
+        }
+        Self::Fatal
+    }
+}
+
+/// how `ProxyServer::run` reacts to errors from the incoming stream
+#[derive(Debug, Clone)]
+pub struct AcceptPolicy {
+    /// first backoff after a resource exhaustion error, doubled on each consecutive one
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    /// back off on fatal errors too instead of stopping the server
+    pub retry_fatal: bool,
+    pub classify: fn(&Error) -> AcceptErrorKind,
+}
+
+impl Default for AcceptPolicy {
+    fn default() -> Self {
+        Self {
+            min_backoff: Duration::from_millis(5),
+            max_backoff: Duration::from_secs(1),
+            retry_fatal: false,
+            classify: AcceptErrorKind::classify,
+        }
+    }
+}
+
+pub(crate) struct Backoff {
+    next: Option<Duration>,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Self { next: None }
+    }
+
+    pub(crate) fn next(&mut self, policy: &AcceptPolicy) -> Duration {
+        let delay = match self.next {
+            Some(delay) => delay.min(policy.max_backoff),
+            None => policy.min_backoff,
+        };
+        self.next = Some(delay * 2);
+        delay
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.next = None;
+    }
+
+    /// when to accept again after `e`, `None` at once, fails if the server must stop
+    pub(crate) fn on_error(
+        &mut self,
+        policy: &AcceptPolicy,
+        e: &Error,
+    ) -> Result<Option<Instant>, ProxyError> {
+        match (policy.classify)(e) {
+            AcceptErrorKind::Transient => {
+                debug!("accept incoming fail: {}", e);
+                Ok(None)
+            }
+            AcceptErrorKind::Fatal if !policy.retry_fatal => {
+                bail!("accept incoming fail: {}", e);
+            }
+            kind => {
+                let delay = self.next(policy);
+                warn!(
+                    "accept incoming fail({:?}): {}, retry in {:?}",
+                    kind, e, delay
+                );
+                Ok(Some(Instant::now() + delay))
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::io::{Error, ErrorKind};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    use super::{AcceptErrorKind, AcceptPolicy};
+    use crate::connector::DirectConnector;
+    use crate::server::ProxyServer;
+
+    #[test]
+    fn test_classify() {
+        let classify = |e: Error| AcceptErrorKind::classify(&e);
+        assert_eq!(
+            classify(Error::from(ErrorKind::ConnectionAborted)),
+            AcceptErrorKind::Transient
+        );
+        assert_eq!(
+            classify(Error::from_raw_os_error(libc::EMFILE)),
+            AcceptErrorKind::ResourceExhausted
+        );
+        assert_eq!(
+            classify(Error::from_raw_os_error(libc::EBADF)),
+            AcceptErrorKind::Fatal
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_accept_errors() {
+        let addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let (mut client, server) = duplex(64);
+        let incoming = tokio_stream::iter(vec![
+            Err(Error::from_raw_os_error(libc::EMFILE)),
+            Err(Error::from_raw_os_error(libc::EMFILE)),
+            Err(Error::from(ErrorKind::ConnectionAborted)),
+            Ok((server, addr)),
+            Err(Error::from_raw_os_error(libc::EBADF)),
+        ]);
+        let policy = AcceptPolicy {
+            min_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let server =
+            ProxyServer::from_incoming(DirectConnector, incoming).with_accept_policy(policy);
+        let start = Instant::now();
+        let result = server.run().await;
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert!(result.is_err());
+
+        client.write_all(b"\x05\x01\x00").await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::address::Address;
+
+/// one record per session, emitted when the session closes
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessRecord {
+    /// close time, unix milliseconds
+    pub timestamp: u64,
+    pub session_id: u64,
+    pub client: SocketAddr,
+    pub user: Option<String>,
+    pub protocol: String,
+    pub method: Option<String>,
+    /// requested target
+    pub target: Option<Address>,
+    /// address passed to the connector, differs from `target` when rewritten by a hook
+    pub upstream: Option<Address>,
+    /// ip of the connected upstream
+    pub resolved: Option<IpAddr>,
+    /// outbound that connected the target, the connector name if the
+    /// session did not connect
+    pub outbound: String,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+    /// `ok`, `aborted` or the `ProxyError` kind
+    pub close: String,
+    pub error: Option<String>,
+}
+
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// destination of access records, called on the task closing the session
+pub trait AccessLogSink: Send + Sync {
+    fn log(&self, record: &AccessRecord);
+}
+
+impl<F> AccessLogSink for F
+where
+    F: Fn(&AccessRecord) + Send + Sync,
+{
+    fn log(&self, record: &AccessRecord) {
+        self(record)
+    }
+}
+
+/// append records as JSON lines, optionally rotated by size
+///
+/// on rotation `path` is renamed to `path.1`, `path.1` to `path.2` and so on,
+/// the oldest beyond `keep` is removed
+pub struct JsonLinesFile {
+    path: PathBuf,
+    rotation: Option<(u64, usize)>,
+    file: Mutex<(File, u64)>,
+}
+
+impl JsonLinesFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path,
+            rotation: None,
+            file: Mutex::new((file, len)),
+        })
+    }
+
+    /// rotate once the file reaches `max_bytes`, keeping `keep` rotated files
+    pub fn with_rotation(mut self, max_bytes: u64, keep: usize) -> Self {
+        self.rotation = Some((max_bytes, keep));
+        self
+    }
+
+    fn rotate(&self, keep: usize) -> Result<File, Error> {
+        let rotated = |i: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{i}"));
+            PathBuf::from(name)
+        };
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..keep).rev() {
+                let from = rotated(i);
+                if from.exists() {
+                    fs::rename(from, rotated(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+    }
+
+    fn write(&self, line: &[u8]) -> Result<(), Error> {
+        let mut file = self.file.lock().unwrap();
+        if let Some((max_bytes, keep)) = self.rotation
+            && file.1 > 0
+            && file.1 + line.len() as u64 > max_bytes
+        {
+            *file = (self.rotate(keep)?, 0);
+        }
+        file.0.write_all(line)?;
+        file.1 += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl AccessLogSink for JsonLinesFile {
+    fn log(&self, record: &AccessRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("serialize access record fail: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = self.write(&line) {
+            warn!("write access log {} fail: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::{AccessLogSink, AccessRecord, JsonLinesFile};
+    use crate::connector::{Connector, DirectConnector, Router};
+    use crate::server::{Rewrite, RewriteRule};
+    use crate::testing::{TempDir, bind, pong_target, request, spawn};
+
+    fn record(session_id: u64) -> AccessRecord {
+        AccessRecord {
+            timestamp: 0,
+            session_id,
+            client: "127.0.0.1:1000".parse().unwrap(),
+            user: None,
+            protocol: "socks5".to_string(),
+            method: Some("CONNECT".to_string()),
+            target: None,
+            upstream: None,
+            resolved: None,
+            outbound: "direct".to_string(),
+            bytes_up: 0,
+            bytes_down: 0,
+            duration_ms: 0,
+            close: "ok".to_string(),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = TempDir::new("access-log");
+        let path = dir.join("access.log");
+        let line_len = serde_json::to_vec(&record(1)).unwrap().len() as u64 + 1;
+
+        let sink = JsonLinesFile::open(&path)
+            .unwrap()
+            .with_rotation(line_len * 2, 2);
+        for id in 1..=7 {
+            sink.log(&record(id));
+        }
+        let ids = |name: &str| -> Vec<u64> {
+            std::fs::read_to_string(dir.join(name))
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+                .map(|v| v["session_id"].as_u64().unwrap())
+                .collect()
+        };
+        assert_eq!(ids("access.log"), vec![7]);
+        assert_eq!(ids("access.log.1"), vec![5, 6]);
+        assert_eq!(ids("access.log.2"), vec![3, 4]);
+        assert!(!dir.join("access.log.3").exists());
+    }
+
+    #[tokio::test]
+    async fn test_access_record() {
+        let target_addr = pong_target().await;
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = {
+            let records = records.clone();
+            move |r: &AccessRecord| records.lock().unwrap().push(r.clone())
+        };
+        // the record names the route, not the router
+        let router = Router::new("local", DirectConnector.make_arc());
+        let port = target_addr.port();
+        let rewrite = RewriteRule::new("api.test:80", &format!("localhost:{port}")).unwrap();
+        let server = bind(router)
+            .await
+            .with_access_log(sink)
+            .with_hook(Rewrite::new().with_rule(rewrite));
+        let proxy = spawn(server);
+
+        let targets = [
+            target_addr.to_string(),
+            format!("localhost:{port}"),
+            "api.test:80".to_string(),
+        ];
+        for target in &targets {
+            request(proxy, target).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 3);
+        for (r, target) in records.iter().zip(&targets) {
+            assert_eq!(&r.target.as_ref().unwrap().to_string(), target);
+            assert_eq!(r.resolved, Some(target_addr.ip()));
+        }
+        let upstream = records[2].upstream.as_ref().unwrap();
+        assert_eq!(upstream.to_string(), format!("localhost:{port}"));
+        let r = &records[0];
+        assert_eq!(r.protocol, "http");
+        assert_eq!(r.method.as_deref(), Some("CONNECT"));
+        assert_eq!(r.outbound, "local");
+        assert_eq!(r.bytes_down, 4);
+        assert_eq!(r.close, "ok");
+    }
+}
@@ -0,0 +1,234 @@
+use std::ops::RangeInclusive;
+
+use crate::address::Address;
+use crate::server::ClientInfo;
+use crate::util::Cidr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// `example.com` matches the domain only, `*.example.com` its subdomains,
+/// `*` any domain, case insensitive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainPattern(String);
+
+impl DomainPattern {
+    pub fn new(pattern: &str) -> Self {
+        Self(pattern.trim_end_matches('.').to_ascii_lowercase())
+    }
+
+    /// suffix matched by a wildcard pattern, e.g. `.example.com`
+    pub(crate) fn wildcard_suffix(&self) -> Option<&str> {
+        self.0.strip_prefix('*')
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        match self.0.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) => domain.ends_with(suffix),
+            None => domain == self.0,
+        }
+    }
+}
+
+/// matches when every non-empty condition matches, each condition matches any of its values
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub action: Action,
+    pub clients: Vec<Cidr>,
+    /// authenticated users, anonymous clients never match
+    pub users: Vec<String>,
+    /// destination domains, IP targets never match
+    pub domains: Vec<DomainPattern>,
+    /// destination ips, domain targets never match
+    pub ips: Vec<Cidr>,
+    pub ports: Vec<RangeInclusive<u16>>,
+}
+
+impl Rule {
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            clients: Vec::new(),
+            users: Vec::new(),
+            domains: Vec::new(),
+            ips: Vec::new(),
+            ports: Vec::new(),
+        }
+    }
+
+    pub fn allow() -> Self {
+        Self::new(Action::Allow)
+    }
+
+    pub fn deny() -> Self {
+        Self::new(Action::Deny)
+    }
+
+    pub fn with_client(mut self, cidr: Cidr) -> Self {
+        self.clients.push(cidr);
+        self
+    }
+
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.users.push(user.to_string());
+        self
+    }
+
+    pub fn with_domain(mut self, pattern: &str) -> Self {
+        self.domains.push(DomainPattern::new(pattern));
+        self
+    }
+
+    pub fn with_ip(mut self, cidr: Cidr) -> Self {
+        self.ips.push(cidr);
+        self
+    }
+
+    pub fn with_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports.push(ports);
+        self
+    }
+
+    fn matches(&self, client: &ClientInfo, target: &Address) -> bool {
+        if !self.clients.is_empty() && !self.clients.iter().any(|c| c.contains(client.addr.ip())) {
+            return false;
+        }
+        if !self.users.is_empty() {
+            match &client.user {
+                Some(user) if self.users.contains(user) => {}
+                _ => return false,
+            }
+        }
+        matches_target(&self.domains, &self.ips, &self.ports, target)
+    }
+}
+
+/// destination conditions shared by acl rules and routes, empty conditions match any target
+pub(crate) fn matches_target(
+    domains: &[DomainPattern],
+    ips: &[Cidr],
+    ports: &[RangeInclusive<u16>],
+    target: &Address,
+) -> bool {
+    let ip = match target {
+        Address::Sock(addr) => Some(addr.ip()),
+        Address::Domain(host, _) => host.parse().ok(),
+    };
+    if !domains.is_empty() {
+        match target {
+            Address::Domain(host, _) if ip.is_none() => {
+                if !domains.iter().any(|d| d.matches(host)) {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+    if !ips.is_empty() {
+        match ip {
+            Some(ip) if ips.iter().any(|c| c.contains(ip)) => {}
+            _ => return false,
+        }
+    }
+    let port = target.port();
+    ports.is_empty() || ports.iter().any(|p| p.contains(&port))
+}
+
+/// ordered rules, the first matching rule decides
+#[derive(Debug, Clone)]
+pub struct Acl {
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+impl Acl {
+    /// `default` applies when no rule matches
+    pub fn new(default: Action) -> Self {
+        Self {
+            rules: Vec::new(),
+            default,
+        }
+    }
+
+    pub fn with_rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn check(&self, client: &ClientInfo, target: &Address) -> Action {
+        self.rules
+            .iter()
+            .find(|r| r.matches(client, target))
+            .map(|r| r.action)
+            .unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::{Acl, Action, Rule};
+    use crate::address::Address;
+    use crate::connector::DirectConnector;
+    use crate::server::ClientInfo;
+    use crate::testing::{bind, request, spawn};
+    use crate::util::Cidr;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_first_match() {
+        let acl = Acl::new(Action::Allow)
+            .with_rule(Rule::allow().with_user("admin"))
+            .with_rule(Rule::deny().with_domain("*.internal"))
+            .with_rule(Rule::deny().with_ip(cidr("10.0.0.0/8")))
+            .with_rule(
+                Rule::deny()
+                    .with_client(cidr("192.168.0.0/16"))
+                    .with_ports(1..=1023),
+            );
+        let client = ClientInfo::new("192.168.1.2:5000".parse().unwrap());
+        let admin = ClientInfo {
+            user: Some("admin".to_string()),
+            ..client.clone()
+        };
+        let other = ClientInfo::new("172.16.0.1:5000".parse().unwrap());
+        let check = |client: &ClientInfo, target: &str| {
+            acl.check(client, &target.parse::<Address>().unwrap())
+        };
+
+        assert_eq!(check(&client, "db.internal:5432"), Action::Deny);
+        assert_eq!(check(&admin, "db.internal:5432"), Action::Allow);
+        assert_eq!(check(&other, "internal:5432"), Action::Allow);
+        assert_eq!(check(&other, "10.1.1.1:8080"), Action::Deny);
+        assert_eq!(check(&client, "example.com:443"), Action::Deny);
+        assert_eq!(check(&client, "example.com:8443"), Action::Allow);
+        assert_eq!(check(&other, "example.com:443"), Action::Allow);
+    }
+
+    #[tokio::test]
+    async fn test_refusal() {
+        let acl = Acl::new(Action::Allow).with_rule(Rule::deny().with_domain("blocked.test"));
+        let proxy = spawn(bind(DirectConnector).await.with_acl(acl));
+
+        let response = request(proxy, "blocked.test:443").await;
+        assert!(response.starts_with(b"HTTP/1.1 403 "));
+
+        let mut sock = TcpStream::connect(proxy).await.unwrap();
+        sock.write_all(b"\x05\x01\x00\x05\x01\x00\x03\x0cblocked.test\x01\xbb")
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        sock.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(&reply[..4], b"\x05\x00\x05\x02");
+    }
+}
@@ -0,0 +1,283 @@
+use std::io::Error;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+
+use tokio::net::{TcpListener, UnixListener};
+use tokio_stream::Stream;
+
+use crate::ProxyError;
+use crate::server::{TcpIncoming, UnixIncoming};
+use crate::transport::BoxedTransport;
+
+/// first fd passed by systemd
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// name of fds without an entry in `LISTEN_FDNAMES`
+const UNKNOWN_NAME: &str = "unknown";
+
+/// fds of `LISTEN_FDS` are owned by the first `ListenFds::from_env`
+static CLAIMED: AtomicBool = AtomicBool::new(false);
+
+/// listening socket inherited from the parent process, TCP or Unix,
+/// usable as the incoming stream of a `ProxyServer` or `Listener`
+pub enum InheritedListener {
+    Tcp(TcpIncoming),
+    Unix(UnixIncoming),
+}
+
+impl InheritedListener {
+    /// take over a listening stream socket, must be called inside a tokio runtime
+    pub fn from_fd(fd: OwnedFd) -> Result<Self, ProxyError> {
+        let raw = fd.as_raw_fd();
+        if sockopt(raw, libc::SO_TYPE).map_err(|e| io_fail!(e, "inspect fd {}", raw))?
+            != libc::SOCK_STREAM
+        {
+            bail!("fd {} is not a stream socket", raw);
+        }
+        if sockopt(raw, libc::SO_ACCEPTCONN).map_err(|e| io_fail!(e, "inspect fd {}", raw))? == 0 {
+            bail!("fd {} is not listening", raw);
+        }
+        set_cloexec(raw).map_err(|e| io_fail!(e, "set close-on-exec on fd {}", raw))?;
+        let family = socket_family(raw).map_err(|e| io_fail!(e, "inspect fd {}", raw))?;
+        match family {
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = std::net::TcpListener::from(fd);
+                listener
+                    .set_nonblocking(true)
+                    .map_err(|e| io_fail!(e, "set nonblocking on fd {}", raw))?;
+                let listener = TcpListener::from_std(listener)
+                    .map_err(|e| io_fail!(e, "register fd {}", raw))?;
+                Ok(InheritedListener::Tcp(TcpIncoming { listener }))
+            }
+            libc::AF_UNIX => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener
+                    .set_nonblocking(true)
+                    .map_err(|e| io_fail!(e, "set nonblocking on fd {}", raw))?;
+                let listener = UnixListener::from_std(listener)
+                    .map_err(|e| io_fail!(e, "register fd {}", raw))?;
+                Ok(InheritedListener::Unix(UnixIncoming::from_listener(
+                    listener,
+                )))
+            }
+            family => bail!("fd {} has unsupported address family {}", raw, family),
+        }
+    }
+}
+
+impl AsFd for InheritedListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            InheritedListener::Tcp(incoming) => incoming.as_fd(),
+            InheritedListener::Unix(incoming) => incoming.as_fd(),
+        }
+    }
+}
+
+impl Stream for InheritedListener {
+    type Item = Result<(BoxedTransport, SocketAddr), Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let accepted = match self.get_mut() {
+            InheritedListener::Tcp(incoming) => ready!(Pin::new(incoming).poll_next(cx))
+                .map(|r| r.map(|(sock, addr)| (Box::new(sock) as BoxedTransport, addr))),
+            InheritedListener::Unix(incoming) => ready!(Pin::new(incoming).poll_next(cx))
+                .map(|r| r.map(|(sock, addr)| (Box::new(sock) as BoxedTransport, addr))),
+        };
+        Poll::Ready(accepted)
+    }
+}
+
+/// sockets passed by systemd socket activation, see `sd_listen_fds(3)`
+pub struct ListenFds {
+    fds: Vec<(String, OwnedFd)>,
+}
+
+impl ListenFds {
+    pub(crate) fn new(fds: Vec<(String, OwnedFd)>) -> Self {
+        Self { fds }
+    }
+
+    /// claim the fds described by `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`,
+    /// empty when the process was not socket activated or the fds were already claimed
+    pub fn from_env() -> Result<Self, ProxyError> {
+        let var = |name| std::env::var(name).ok();
+        let fds = parse_listen_fds(
+            var("LISTEN_PID").as_deref(),
+            var("LISTEN_FDS").as_deref(),
+            var("LISTEN_FDNAMES").as_deref(),
+            std::process::id(),
+        )?;
+        if fds.is_empty() || CLAIMED.swap(true, Ordering::SeqCst) {
+            return Ok(Self { fds: Vec::new() });
+        }
+        let fds = fds
+            .into_iter()
+            // SAFETY: systemd passes these fds to this process, which claims them once
+            .map(|(fd, name)| (name, unsafe { OwnedFd::from_raw_fd(fd) }))
+            .collect();
+        Ok(Self { fds })
+    }
+
+    pub fn len(&self) -> usize {
+        self.fds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    /// names from `FileDescriptorName=`, `unknown` when not set
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.fds.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// take the first remaining listener named `name`
+    pub fn take(&mut self, name: &str) -> Result<Option<InheritedListener>, ProxyError> {
+        match self.fds.iter().position(|(n, _)| n == name) {
+            Some(i) => InheritedListener::from_fd(self.fds.remove(i).1).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// take all remaining listeners in order
+    pub fn take_all(&mut self) -> Result<Vec<(String, InheritedListener)>, ProxyError> {
+        mem::take(&mut self.fds)
+            .into_iter()
+            .map(|(name, fd)| Ok((name, InheritedListener::from_fd(fd)?)))
+            .collect()
+    }
+}
+
+/// fds and names meant for `pid`, empty when they are meant for another process
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Result<Vec<(RawFd, String)>, ProxyError> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(Vec::new());
+    };
+    match listen_pid.trim().parse::<u32>() {
+        Ok(listen_pid) if listen_pid == pid => {}
+        Ok(_) => return Ok(Vec::new()),
+        Err(_) => return Err(invalid_data!("invalid LISTEN_PID {:?}", listen_pid)),
+    }
+    let count: RawFd = match listen_fds.trim().parse() {
+        Ok(count) if count >= 0 => count,
+        _ => return Err(invalid_data!("invalid LISTEN_FDS {:?}", listen_fds)),
+    };
+    let mut names = listen_fdnames.map(|n| n.split(':')).into_iter().flatten();
+    Ok((0..count)
+        .map(|i| {
+            let name = match names.next() {
+                Some(name) if !name.is_empty() => name,
+                _ => UNKNOWN_NAME,
+            };
+            (SD_LISTEN_FDS_START + i, name.to_string())
+        })
+        .collect())
+}
+
+fn sockopt(fd: RawFd, opt: libc::c_int) -> Result<libc::c_int, Error> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value and len describe a valid c_int buffer
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            opt,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(value)
+}
+
+fn socket_family(fd: RawFd) -> Result<libc::c_int, Error> {
+    // SAFETY: sockaddr_storage is plain old data
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: addr and len describe a valid sockaddr_storage buffer
+    let ret = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(addr.ss_family as libc::c_int)
+}
+
+/// systemd passes fds without close-on-exec, keep them from leaking into children
+fn set_cloexec(fd: RawFd) -> Result<(), Error> {
+    // SAFETY: fcntl on an owned fd
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(Error::last_os_error());
+    }
+    // SAFETY: fcntl on an owned fd
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::fd::OwnedFd;
+
+    use super::{InheritedListener, parse_listen_fds};
+    use crate::connector::DirectConnector;
+    use crate::server::ProxyServer;
+    use crate::testing::{pong_target, request};
+
+    #[test]
+    fn test_parse_listen_fds() {
+        let fds = parse_listen_fds(Some("42"), Some("3"), Some("http:socks"), 42).unwrap();
+        assert_eq!(
+            fds,
+            vec![
+                (3, "http".to_string()),
+                (4, "socks".to_string()),
+                (5, "unknown".to_string())
+            ]
+        );
+        assert!(
+            parse_listen_fds(Some("41"), Some("3"), None, 42)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(parse_listen_fds(None, None, None, 42).unwrap().is_empty());
+        assert!(parse_listen_fds(Some("42"), Some("x"), None, 42).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inherited_listener() {
+        let target_addr = pong_target().await;
+        let not_listening = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(InheritedListener::from_fd(OwnedFd::from(not_listening)).is_err());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let inherited = InheritedListener::from_fd(OwnedFd::from(listener)).unwrap();
+        assert!(matches!(inherited, InheritedListener::Tcp(_)));
+        let server = ProxyServer::from_incoming(DirectConnector, inherited);
+        tokio::spawn(server.run());
+
+        let data = request(proxy, target_addr).await;
+        assert_eq!(data, b"HTTP/1.1 200 Ok\r\n\r\npong");
+    }
+}
@@ -0,0 +1,394 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::ProxyError;
+use crate::address::Address;
+use crate::connector::OutboundHealth;
+use crate::server::SessionRegistry;
+use crate::server::auth::constant_time_eq;
+use crate::util::BufIoExt;
+
+/// max bytes of a request head
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// max bytes of a request body
+const MAX_BODY_LEN: usize = 4 * 1024;
+
+/// time a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Provider<T> = Box<dyn Fn() -> T + Send + Sync>;
+
+/// sets the log level, fails with a message for unknown levels
+type SetLevel = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+struct LogLevel {
+    get: Provider<String>,
+    set: SetLevel,
+}
+
+/// JSON admin API of a running server
+///
+/// - `GET /sessions` active sessions, the most bytes relayed first
+/// - `DELETE /sessions/<id>` kill a session
+/// - `DELETE /sessions?target=<host>[:<port>]` kill all sessions to a destination
+/// - `GET /outbounds` outbound health, see `with_outbounds`
+/// - `GET /config` effective config, see `with_config`
+/// - `GET /log/level`, `PUT /log/level` with `{"level": "debug"}`, see `with_log_level`
+pub struct Admin {
+    registry: SessionRegistry,
+    /// required as `Authorization: Bearer <token>` when set
+    token: Option<String>,
+    outbounds: Option<Provider<Vec<OutboundHealth>>>,
+    config: Option<Provider<Value>>,
+    log_level: Option<LogLevel>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    authorized: bool,
+    body: Vec<u8>,
+}
+
+impl Admin {
+    pub fn new(registry: SessionRegistry) -> Self {
+        Self {
+            registry,
+            token: None,
+            outbounds: None,
+            config: None,
+            log_level: None,
+        }
+    }
+
+    /// require `Authorization: Bearer <token>` on every request
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// serve `GET /outbounds` from `f`
+    pub fn with_outbounds<F>(mut self, f: F) -> Self
+    where
+        F: Fn() -> Vec<OutboundHealth> + Send + Sync + 'static,
+    {
+        self.outbounds = Some(Box::new(f));
+        self
+    }
+
+    /// serve `GET /config` from `f`, secrets should be redacted
+    pub fn with_config<F>(mut self, f: F) -> Self
+    where
+        F: Fn() -> Value + Send + Sync + 'static,
+    {
+        self.config = Some(Box::new(f));
+        self
+    }
+
+    /// serve `GET /log/level` from `get` and `PUT /log/level` with `set`
+    pub fn with_log_level<G, S>(mut self, get: G, set: S) -> Self
+    where
+        G: Fn() -> String + Send + Sync + 'static,
+        S: Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.log_level = Some(LogLevel {
+            get: Box::new(get),
+            set: Box::new(set),
+        });
+        self
+    }
+
+    /// serve the API on `listener` until it fails
+    pub async fn serve(self, listener: TcpListener) -> Result<(), ProxyError> {
+        let admin = Arc::new(self);
+        loop {
+            let (sock, addr) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => return Err(io_fail!(e, "accept admin listener")),
+            };
+            let admin = admin.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin.handle(sock).await {
+                    debug!("admin request from {} fail: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, sock: TcpStream) -> Result<(), ProxyError> {
+        let mut io = BufReader::new(sock);
+        let request = tokio::time::timeout(REQUEST_TIMEOUT, self.read_request(&mut io))
+            .await
+            .map_err(|_| ProxyError::HandshakeTimeout)??;
+        let (status, body) = if request.authorized {
+            self.route(&request)
+        } else {
+            ("401 Unauthorized", json!({"error": "unauthorized"}))
+        };
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        io.write_all(response.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn read_request(&self, io: &mut BufReader<TcpStream>) -> Result<Request, ProxyError> {
+        let head_line = io.read_until_bytes(b"\r\n").await?;
+        let head_line = String::from_utf8_lossy(&head_line);
+        let mut parts = head_line.split(' ');
+        let method = parts.next().unwrap_or_default().to_string();
+        let target = parts.next().unwrap_or_default();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut len = head_line.len();
+        let mut content_length = 0;
+        let mut authorized = self.token.is_none();
+        loop {
+            let line = io.read_until_bytes(b"\r\n").await?;
+            if line.len() <= 2 {
+                break;
+            }
+            len += line.len();
+            if len > MAX_REQUEST_LEN {
+                return Err(invalid_data!("admin request head too long"));
+            }
+            let line = String::from_utf8_lossy(&line);
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .parse()
+                    .map_err(|_| invalid_data!("invalid content length {:?}", value))?;
+            } else if name.eq_ignore_ascii_case("authorization")
+                && let (Some(token), Some(bearer)) = (&self.token, value.strip_prefix("Bearer "))
+            {
+                authorized = constant_time_eq(token.as_bytes(), bearer.trim().as_bytes());
+            }
+        }
+        if content_length > MAX_BODY_LEN {
+            return Err(invalid_data!("admin request body too long"));
+        }
+        let mut body = vec![0; content_length];
+        io.read_exact(&mut body).await?;
+        Ok(Request {
+            method,
+            path: path.to_string(),
+            query: url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect(),
+            authorized,
+            body,
+        })
+    }
+
+    fn route(&self, request: &Request) -> (&'static str, Value) {
+        let path = request.path.trim_end_matches('/');
+        match (request.method.as_str(), path) {
+            ("GET", "/sessions") => ("200 OK", self.sessions()),
+            ("DELETE", "/sessions") => match request.query.iter().find(|(k, _)| k == "target") {
+                Some((_, target)) => ("200 OK", json!({"killed": self.kill_target(target)})),
+                None => bad_request("missing target"),
+            },
+            ("DELETE", _) if path.starts_with("/sessions/") => {
+                match path["/sessions/".len()..].parse() {
+                    Ok(id) if self.registry.kill(id) => ("200 OK", json!({"killed": 1})),
+                    Ok(_) => not_found(),
+                    Err(_) => bad_request("invalid session id"),
+                }
+            }
+            ("GET", "/outbounds") => match &self.outbounds {
+                Some(outbounds) => ("200 OK", json!({"outbounds": outbounds()})),
+                None => not_found(),
+            },
+            ("GET", "/config") => match &self.config {
+                Some(config) => ("200 OK", config()),
+                None => not_found(),
+            },
+            ("GET", "/log/level") => match &self.log_level {
+                Some(log_level) => ("200 OK", json!({"level": (log_level.get)()})),
+                None => not_found(),
+            },
+            ("PUT", "/log/level") => match &self.log_level {
+                Some(log_level) => {
+                    let level = serde_json::from_slice::<Value>(&request.body)
+                        .ok()
+                        .and_then(|v| v["level"].as_str().map(str::to_string));
+                    match level.map(|level| (log_level.set)(&level)) {
+                        Some(Ok(())) => ("200 OK", json!({"level": (log_level.get)()})),
+                        Some(Err(e)) => bad_request(&e),
+                        None => bad_request("expected {\"level\": <level>}"),
+                    }
+                }
+                None => not_found(),
+            },
+            _ => not_found(),
+        }
+    }
+
+    fn sessions(&self) -> Value {
+        let now = SystemTime::now();
+        let mut sessions = self.registry.sessions();
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.bytes_up + s.bytes_down));
+        let sessions: Vec<_> = sessions
+            .into_iter()
+            .map(|s| {
+                json!({
+                    "id": s.id,
+                    "client": s.client.addr,
+                    "user": s.client.user,
+                    "protocol": s.protocol,
+                    "method": s.method,
+                    "target": s.target.map(|t| t.to_string()),
+                    "upstream": s.upstream.map(|t| t.to_string()),
+                    "resolved": s.resolved,
+                    "outbound": s.outbound,
+                    "start_ms": s.start.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+                    "duration_ms": now.duration_since(s.start).map_or(0, |d| d.as_millis() as u64),
+                    "bytes_up": s.bytes_up,
+                    "bytes_down": s.bytes_down,
+                })
+            })
+            .collect();
+        json!({"sessions": sessions})
+    }
+
+    /// kill the sessions to `target`, a host matches any port
+    fn kill_target(&self, target: &str) -> usize {
+        let addr: Option<Address> = target.parse().ok();
+        let host = addr.as_ref().map_or(target.to_string(), Address::host);
+        self.registry.kill_matching(|s| match &s.target {
+            Some(t) => t.host() == host && addr.as_ref().is_none_or(|a| a.port() == t.port()),
+            None => false,
+        })
+    }
+}
+
+fn not_found() -> (&'static str, Value) {
+    ("404 Not Found", json!({"error": "not found"}))
+}
+
+fn bad_request(message: &str) -> (&'static str, Value) {
+    ("400 Bad Request", json!({"error": message}))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::{Value, json};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::Admin;
+    use crate::connector::DirectConnector;
+    use crate::testing::{bind, echo_target, open_tunnel, spawn};
+
+    async fn request(admin: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let mut sock = TcpStream::connect(admin).await.unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        sock.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        sock.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let target_addr = echo_target().await;
+        let server = bind(DirectConnector).await;
+        let level = Arc::new(Mutex::new("info".to_string()));
+        let (get, set) = (level.clone(), level.clone());
+        let admin = Admin::new(server.registry())
+            .with_token("secret")
+            .with_config(|| json!({"listeners": []}))
+            .with_log_level(
+                move || get.lock().unwrap().clone(),
+                move |l| match l {
+                    "info" | "debug" => {
+                        *set.lock().unwrap() = l.to_string();
+                        Ok(())
+                    }
+                    _ => Err(format!("unknown level {l:?}")),
+                },
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_addr = listener.local_addr().unwrap();
+        tokio::spawn(admin.serve(listener));
+        let proxy = spawn(server);
+
+        let mut socks = Vec::new();
+        for payload in [&b"ping"[..], &b"a larger payload"[..]] {
+            let mut sock = open_tunnel(proxy, target_addr).await.unwrap();
+            sock.write_all(payload).await.unwrap();
+            let mut buf = vec![0; payload.len()];
+            sock.read_exact(&mut buf).await.unwrap();
+            socks.push(sock);
+        }
+
+        let mut sock = TcpStream::connect(admin_addr).await.unwrap();
+        sock.write_all(b"GET /sessions HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        sock.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        let (status, body) = request(admin_addr, "GET", "/sessions", "").await;
+        assert_eq!(status, 200);
+        let sessions = body["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0]["bytes_up"], 16);
+        assert_eq!(sessions[1]["bytes_down"], 4);
+        assert_eq!(sessions[0]["target"], target_addr.to_string());
+
+        let id = sessions[1]["id"].as_u64().unwrap();
+        let (status, _) = request(admin_addr, "DELETE", &format!("/sessions/{id}"), "").await;
+        assert_eq!(status, 200);
+        let mut buf = Vec::new();
+        socks[0].read_to_end(&mut buf).await.unwrap();
+        let path = format!("/sessions?target={}", target_addr.ip());
+        let (status, body) = request(admin_addr, "DELETE", &path, "").await;
+        assert_eq!((status, body), (200, json!({"killed": 1})));
+        socks[1].read_to_end(&mut buf).await.unwrap();
+
+        assert_eq!(request(admin_addr, "GET", "/outbounds", "").await.0, 404);
+        let (_, body) = request(admin_addr, "GET", "/config", "").await;
+        assert_eq!(body, json!({"listeners": []}));
+        let (status, body) = request(admin_addr, "PUT", "/log/level", r#"{"level":"debug"}"#).await;
+        assert_eq!((status, body), (200, json!({"level": "debug"})));
+        let (status, _) = request(admin_addr, "PUT", "/log/level", r#"{"level":"loud"}"#).await;
+        assert_eq!(status, 400);
+        assert_eq!(*level.lock().unwrap(), "debug");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_timeout() {
+        let server = bind(DirectConnector).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_addr = listener.local_addr().unwrap();
+        tokio::spawn(Admin::new(server.registry()).serve(listener));
+
+        let mut sock = TcpStream::connect(admin_addr).await.unwrap();
+        sock.write_all(b"GET /sessions HTTP/1.1\r\n").await.unwrap();
+        let mut response = Vec::new();
+        sock.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// username and password pairs accepted by the socks5 and http handles
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    /// password digests by user
+    users: HashMap<String, [u8; 32]>,
+}
+
+impl Credentials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_user(mut self, user: &str, password: &str) -> Self {
+        self.users
+            .insert(user.to_string(), digest(password.as_bytes()));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// unknown users are compared like known ones
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let supplied = digest(password.as_bytes());
+        let (expected, known) = match self.users.get(user) {
+            Some(expected) => (expected, true),
+            None => (&[0; 32], false),
+        };
+        bool::from(expected.ct_eq(&supplied)) & known
+    }
+}
+
+fn digest(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// compare fixed-size digests, leaks neither the contents nor the lengths
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    digest(a).ct_eq(&digest(b)).into()
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::{Credentials, constant_time_eq};
+    use crate::connector::DirectConnector;
+    use crate::server::{Acl, Action, Rule};
+    use crate::testing::{bind, echo_target, request, spawn};
+
+    #[test]
+    fn test_verify() {
+        let credentials = Credentials::new().with_user("alice", "secret");
+        assert!(credentials.verify("alice", "secret"));
+        assert!(!credentials.verify("alice", "secre"));
+        assert!(!credentials.verify("alice", "secret!"));
+        assert!(!credentials.verify("bob", "secret"));
+        // an unknown user never matches the placeholder digest
+        assert!(!credentials.verify("bob", ""));
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+    }
+
+    #[tokio::test]
+    async fn test_password_auth() {
+        let target_addr = echo_target().await;
+        let credentials = Credentials::new().with_user("alice", "secret");
+        // only authenticated users reach the target
+        let acl = Acl::new(Action::Deny).with_rule(Rule::allow().with_user("alice"));
+        let server = bind(DirectConnector)
+            .await
+            .with_credentials(credentials)
+            .with_acl(acl);
+        let proxy = spawn(server);
+
+        let response = String::from_utf8(request(proxy, target_addr).await).unwrap();
+        assert!(response.starts_with("HTTP/1.1 407 "));
+        assert!(response.contains("Proxy-Authenticate: Basic"));
+
+        // alice:secret
+        let mut sock = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "GET http://{target_addr}/ HTTP/1.1\r\nProxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\nX-A: 1\r\n\r\n"
+        );
+        sock.write_all(request.as_bytes()).await.unwrap();
+        let mut buf = [0; 64];
+        let n = sock.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"GET / HTTP/1.1\r\nX-A: 1\r\n\r\n");
+
+        let mut sock = TcpStream::connect(proxy).await.unwrap();
+        sock.write_all(b"\x05\x01\x00").await.unwrap();
+        let mut reply = [0; 2];
+        sock.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0xff]);
+
+        let mut sock = TcpStream::connect(proxy).await.unwrap();
+        sock.write_all(b"\x05\x01\x02\x01\x05alice\x05wrong")
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        sock.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"\x05\x02\x01\x01");
+
+        let mut sock = TcpStream::connect(proxy).await.unwrap();
+        let mut request =
+            b"\x05\x01\x02\x01\x05alice\x06secret\x05\x01\x00\x01\x7f\x00\x00\x01".to_vec();
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        sock.write_all(&request).await.unwrap();
+        let mut reply = [0; 14];
+        sock.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..6], b"\x05\x02\x01\x00\x05\x00");
+        sock.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        sock.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::server::ClientInfo;
+use crate::util::{DuplexCopy, TokenBucket};
+
+/// number of tracked users before buckets of finished sessions are dropped
+const PRUNE_USERS_LEN: usize = 1024;
+
+/// byte rate, `rate` must be positive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bandwidth {
+    /// bytes per second
+    pub rate: u64,
+    /// max bytes sent at once after being idle
+    pub burst: u64,
+}
+
+impl Bandwidth {
+    /// `rate` bytes per second with one second of burst
+    pub fn new(rate: u64) -> Self {
+        Self { rate, burst: rate }
+    }
+}
+
+/// upload is data from the client to the target, download the reverse
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BandwidthLimit {
+    pub upload: Option<Bandwidth>,
+    pub download: Option<Bandwidth>,
+}
+
+/// rate limits of relayed data, a session is shaped by all limits that apply to it
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimits {
+    /// shared by all sessions of the server
+    pub global: BandwidthLimit,
+    /// shared by sessions of the same authenticated user, anonymous clients are not limited
+    pub per_user: BandwidthLimit,
+    /// of each session
+    pub per_session: BandwidthLimit,
+}
+
+#[derive(Clone, Default)]
+struct Buckets {
+    upload: Option<Arc<TokenBucket>>,
+    download: Option<Arc<TokenBucket>>,
+}
+
+impl Buckets {
+    fn new(limit: &BandwidthLimit) -> Self {
+        let bucket = |b: Bandwidth| Arc::new(TokenBucket::new(b.rate, b.burst));
+        Self {
+            upload: limit.upload.map(bucket),
+            download: limit.download.map(bucket),
+        }
+    }
+
+    fn in_use(&self) -> bool {
+        let shared =
+            |b: &Option<Arc<TokenBucket>>| b.as_ref().is_some_and(|b| Arc::strong_count(b) > 1);
+        shared(&self.upload) || shared(&self.download)
+    }
+
+    fn apply<L, R>(&self, mut copy: DuplexCopy<L, R>) -> DuplexCopy<L, R>
+    where
+        L: AsyncRead + AsyncWrite,
+        R: AsyncRead + AsyncWrite,
+    {
+        if let Some(upload) = &self.upload {
+            copy = copy.with_left_limit(upload.clone());
+        }
+        if let Some(download) = &self.download {
+            copy = copy.with_right_limit(download.clone());
+        }
+        copy
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Shaper {
+    limits: BandwidthLimits,
+    global: Buckets,
+    users: Mutex<HashMap<String, Buckets>>,
+}
+
+impl Shaper {
+    pub(crate) fn new(limits: BandwidthLimits) -> Self {
+        Self {
+            global: Buckets::new(&limits.global),
+            limits,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// attach the buckets of `client` to a relay whose left side is the client
+    pub(crate) fn apply<L, R>(
+        &self,
+        client: &ClientInfo,
+        copy: DuplexCopy<L, R>,
+    ) -> DuplexCopy<L, R>
+    where
+        L: AsyncRead + AsyncWrite,
+        R: AsyncRead + AsyncWrite,
+    {
+        let copy = self.global.apply(copy);
+        let copy = Buckets::new(&self.limits.per_session).apply(copy);
+        let per_user = &self.limits.per_user;
+        match &client.user {
+            Some(user) if per_user.upload.is_some() || per_user.download.is_some() => {
+                let buckets = {
+                    let mut users = self.users.lock().unwrap();
+                    if !users.contains_key(user) && users.len() >= PRUNE_USERS_LEN {
+                        users.retain(|_, b| b.in_use());
+                    }
+                    users
+                        .entry(user.clone())
+                        .or_insert_with(|| Buckets::new(per_user))
+                        .clone()
+                };
+                buckets.apply(copy)
+            }
+            _ => copy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+    use tokio::time::Instant;
+
+    use super::{Bandwidth, BandwidthLimit, BandwidthLimits, Shaper};
+    use crate::server::ClientInfo;
+    use crate::util::DuplexCopy;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shape_upload() {
+        let shaper = Shaper::new(BandwidthLimits {
+            per_session: BandwidthLimit {
+                upload: Some(Bandwidth {
+                    rate: 64 * 1024,
+                    burst: 16 * 1024,
+                }),
+                download: None,
+            },
+            ..Default::default()
+        });
+        let client = ClientInfo::new("127.0.0.1:1000".parse().unwrap());
+        let (mut local, local_peer) = duplex(64 * 1024);
+        let (mut remote, remote_peer) = duplex(64 * 1024);
+        let copy = shaper.apply(&client, DuplexCopy::new(local_peer, remote_peer));
+        tokio::spawn(copy);
+
+        // 16KiB burst, then 32KiB at 64KiB/s, in paused time
+        let start = Instant::now();
+        let data = vec![1u8; 48 * 1024];
+        let write = async {
+            local.write_all(&data).await.unwrap();
+            local.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut received = Vec::new();
+            remote.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let (_, received) = tokio::join!(write, read);
+        assert_eq!(received.len(), data.len());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(500), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(550), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shaped_not_idle() {
+        let shaper = Shaper::new(BandwidthLimits {
+            per_session: BandwidthLimit {
+                upload: Some(Bandwidth {
+                    rate: 1024,
+                    burst: 1024,
+                }),
+                download: None,
+            },
+            ..Default::default()
+        });
+        let client = ClientInfo::new("127.0.0.1:1000".parse().unwrap());
+        let (mut local, local_peer) = duplex(64 * 1024);
+        let (mut remote, remote_peer) = duplex(64 * 1024);
+        // each grant waits 1s for tokens, far longer than the idle timeout
+        let copy = shaper
+            .apply(&client, DuplexCopy::new(local_peer, remote_peer))
+            .with_idle_timeout(Duration::from_millis(100));
+        let copy = tokio::spawn(copy);
+
+        let data = vec![1u8; 4 * 1024];
+        local.write_all(&data).await.unwrap();
+        let mut received = vec![0; data.len()];
+        remote.read_exact(&mut received).await.unwrap();
+        local.shutdown().await.unwrap();
+        remote.shutdown().await.unwrap();
+        assert!(copy.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,332 @@
+use std::fs::Permissions;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::net::UnixStream;
+
+use crate::ProxyError;
+use crate::server::unix::bind_unix;
+use crate::server::{ListenFds, ShutdownHandle};
+
+/// max listeners passed in one handoff
+const MAX_HANDOFF_FDS: usize = 64;
+
+/// max length of the listener names
+const MAX_NAMES_LEN: usize = 4096;
+
+/// time the new process has to take over after receiving the listeners
+const ACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// byte sent by the new process once it accepts on the received listeners
+const ACK: u8 = b'1';
+
+/// pass listening sockets to a new process over a unix socket
+///
+/// the new process connects with `HandoffReceiver`, receives the listeners with
+/// `SCM_RIGHTS` and acknowledges once it accepts on them, then this server stops
+/// accepting and drains its sessions, so no connection is refused during an upgrade
+pub struct Handoff {
+    listeners: Vec<(String, OwnedFd)>,
+    shutdown: ShutdownHandle,
+    grace: Duration,
+}
+
+impl Handoff {
+    /// on handoff, shut down with `grace`, see `ProxyServer::shutdown_handle`
+    pub fn new(shutdown: ShutdownHandle, grace: Duration) -> Self {
+        Self {
+            listeners: Vec::new(),
+            shutdown,
+            grace,
+        }
+    }
+
+    /// hand off `listener` as `name`, the name must not contain `:`
+    pub fn with_listener<F: AsFd>(mut self, name: &str, listener: &F) -> Result<Self, ProxyError> {
+        if name.is_empty() || name.contains(':') {
+            return Err(invalid_data!("invalid listener name {:?}", name));
+        }
+        if self.listeners.len() >= MAX_HANDOFF_FDS {
+            bail!("too many listeners for handoff");
+        }
+        let fd = listener
+            .as_fd()
+            .try_clone_to_owned()
+            .map_err(|e| io_fail!(e, "duplicate listener {}", name))?;
+        self.listeners.push((name.to_string(), fd));
+        Ok(self)
+    }
+
+    /// serve handoff requests on `path` until one completes, the socket is only
+    /// accessible to the owner and peers running as another user are refused
+    pub async fn serve<P: AsRef<Path>>(self, path: P) -> Result<(), ProxyError> {
+        if self.listeners.is_empty() {
+            bail!("no listeners to hand off");
+        }
+        let path = path.as_ref();
+        let listener =
+            bind_unix(path).map_err(|e| io_fail!(e, "bind handoff {}", path.display()))?;
+        std::fs::set_permissions(path, Permissions::from_mode(0o600))
+            .map_err(|e| io_fail!(e, "chmod handoff {}", path.display()))?;
+        // SAFETY: geteuid has no preconditions
+        let uid = unsafe { libc::geteuid() };
+        loop {
+            let (mut sock, _) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => return Err(io_fail!(e, "accept handoff listener")),
+            };
+            match sock.peer_cred() {
+                Ok(cred) if cred.uid() == uid => {}
+                Ok(cred) => {
+                    warn!("refuse handoff to uid {}", cred.uid());
+                    continue;
+                }
+                Err(e) => {
+                    warn!("get handoff peer credentials fail: {}", e);
+                    continue;
+                }
+            }
+            match self.hand_over(&mut sock).await {
+                Ok(()) => {
+                    info!("{} listeners handed off, shutdown", self.listeners.len());
+                    self.shutdown.shutdown(self.grace);
+                    return Ok(());
+                }
+                Err(e) => warn!("handoff fail, keep accepting: {}", e),
+            }
+        }
+    }
+
+    async fn hand_over(&self, sock: &mut UnixStream) -> Result<(), ProxyError> {
+        let names: Vec<&str> = self.listeners.iter().map(|(n, _)| n.as_str()).collect();
+        let fds: Vec<RawFd> = self
+            .listeners
+            .iter()
+            .map(|(_, fd)| fd.as_raw_fd())
+            .collect();
+        send_fds(sock, names.join(":").as_bytes(), &fds)
+            .await
+            .map_err(|e| io_fail!(e, "send listeners"))?;
+        let mut ack = [0];
+        match tokio::time::timeout(ACK_TIMEOUT, sock.read_exact(&mut ack)).await {
+            Ok(Ok(_)) if ack[0] == ACK => Ok(()),
+            Ok(Ok(_)) => Err(protocol_fail!("unexpected handoff ack {:#x}", ack[0])),
+            Ok(Err(e)) => Err(io_fail!(e, "read handoff ack")),
+            Err(_) => Err(protocol_fail!("handoff ack timeout")),
+        }
+    }
+}
+
+/// listeners received from a running server, see `Handoff`
+pub struct HandoffReceiver {
+    sock: UnixStream,
+    listeners: ListenFds,
+}
+
+impl HandoffReceiver {
+    pub async fn connect<P: AsRef<Path>>(path: P) -> Result<Self, ProxyError> {
+        let path = path.as_ref();
+        let sock = UnixStream::connect(path)
+            .await
+            .map_err(|e| io_fail!(e, "connect handoff {}", path.display()))?;
+        let (names, fds) = recv_fds(&sock)
+            .await
+            .map_err(|e| io_fail!(e, "receive listeners"))?;
+        let names =
+            String::from_utf8(names).map_err(|_| invalid_data!("invalid listener names"))?;
+        let names: Vec<&str> = names.split(':').collect();
+        if names.len() != fds.len() {
+            return Err(protocol_fail!(
+                "received {} listeners for {} names",
+                fds.len(),
+                names.len()
+            ));
+        }
+        let fds = names.into_iter().map(str::to_string).zip(fds).collect();
+        Ok(Self {
+            sock,
+            listeners: ListenFds::new(fds),
+        })
+    }
+
+    /// received listeners by name
+    pub fn listeners(&mut self) -> &mut ListenFds {
+        &mut self.listeners
+    }
+
+    /// tell the old server to stop accepting, call once the new server runs
+    pub async fn complete(mut self) -> Result<(), ProxyError> {
+        self.sock
+            .write_all(&[ACK])
+            .await
+            .map_err(|e| io_fail!(e, "send handoff ack"))
+    }
+}
+
+async fn send_fds(sock: &UnixStream, data: &[u8], fds: &[RawFd]) -> Result<(), Error> {
+    loop {
+        sock.writable().await?;
+        match sock.try_io(Interest::WRITABLE, || sendmsg(sock.as_raw_fd(), data, fds)) {
+            Ok(n) if n == data.len() => return Ok(()),
+            Ok(_) => return Err(Error::new(ErrorKind::WriteZero, "short handoff message")),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn recv_fds(sock: &UnixStream) -> Result<(Vec<u8>, Vec<OwnedFd>), Error> {
+    let mut buf = vec![0; MAX_NAMES_LEN];
+    loop {
+        sock.readable().await?;
+        match sock.try_io(Interest::READABLE, || recvmsg(sock.as_raw_fd(), &mut buf)) {
+            Ok((0, _)) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+            Ok((n, fds)) => {
+                buf.truncate(n);
+                return Ok((buf, fds));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// control buffer for `n` fds, `u64` keeps it aligned for `cmsghdr`
+fn cmsg_buffer(n: usize) -> Vec<u64> {
+    // SAFETY: CMSG_SPACE only computes a length
+    let space = unsafe { libc::CMSG_SPACE((n * mem::size_of::<RawFd>()) as u32) } as usize;
+    vec![0; space.div_ceil(mem::size_of::<u64>())]
+}
+
+fn sendmsg(fd: RawFd, data: &[u8], fds: &[RawFd]) -> Result<usize, Error> {
+    let fds_len = mem::size_of_val(fds);
+    let mut cmsg = cmsg_buffer(fds.len());
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    // SAFETY: msghdr is plain old data
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = (cmsg.len() * mem::size_of::<u64>()) as _;
+        // SAFETY: the control buffer has room for one header with `fds`
+        unsafe {
+            let header = libc::CMSG_FIRSTHDR(&msg);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr() as *const u8, libc::CMSG_DATA(header), fds_len);
+        }
+    }
+    // SAFETY: msg points to live buffers
+    let n = unsafe { libc::sendmsg(fd, &msg, 0) };
+    if n < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+fn recvmsg(fd: RawFd, buf: &mut [u8]) -> Result<(usize, Vec<OwnedFd>), Error> {
+    let mut cmsg = cmsg_buffer(MAX_HANDOFF_FDS);
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // SAFETY: msghdr is plain old data
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = (cmsg.len() * mem::size_of::<u64>()) as _;
+    // SAFETY: msg points to live buffers
+    let n = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if n < 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut fds = Vec::new();
+    // SAFETY: the headers were filled by recvmsg and stay within the control buffer
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&msg);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(header) as *const RawFd;
+                let len = (*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    // the received fds are new and owned by this process
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            header = libc::CMSG_NXTHDR(&msg, header);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "too many fds"));
+    }
+    Ok((n as usize, fds))
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    use super::{Handoff, HandoffReceiver};
+    use crate::connector::DirectConnector;
+    use crate::server::{Metrics, ProxyServer};
+    use crate::testing::{TempDir, bind, pong_target, request};
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let target_addr = pong_target().await;
+        let dir = TempDir::new("handoff");
+        let path = dir.join("handoff.sock");
+
+        let old_metrics = Metrics::new();
+        let old = bind(DirectConnector).await;
+        let proxy = old.incoming().local_addr().unwrap();
+        let handoff = Handoff::new(old.shutdown_handle(), Duration::from_secs(1))
+            .with_listener("main", old.incoming())
+            .unwrap();
+        let old = tokio::spawn(old.with_metrics(old_metrics.clone()).run());
+        tokio::spawn(handoff.serve(path.clone()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(request(proxy, target_addr).await.ends_with(b"pong"));
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut receiver = HandoffReceiver::connect(&path).await.unwrap();
+        assert_eq!(
+            receiver.listeners().names().collect::<Vec<_>>(),
+            vec!["main"]
+        );
+        let inherited = receiver.listeners().take("main").unwrap().unwrap();
+        let new_metrics = Metrics::new();
+        let new = ProxyServer::from_incoming(DirectConnector, inherited)
+            .with_metrics(new_metrics.clone());
+        tokio::spawn(new.run());
+        receiver.complete().await.unwrap();
+
+        let summary = old.await.unwrap().unwrap();
+        assert_eq!(summary.aborted, 0);
+        assert!(request(proxy, target_addr).await.ends_with(b"pong"));
+        assert!(
+            old_metrics
+                .render()
+                .contains("proxy_accepted_connections_total 1\n")
+        );
+        assert!(
+            new_metrics
+                .render()
+                .contains("proxy_accepted_connections_total 1\n")
+        );
+    }
+}
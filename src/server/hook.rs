@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::ProxyError;
+use crate::address::Address;
+use crate::server::{AccessRecord, ClientInfo, SessionInfo};
+
+/// callbacks around each session, e.g. for auditing, custom policy or rewriting
+/// targets, see `ProxyServer::with_hook`
+///
+/// hooks run in registration order, an error from a hook refuses the session
+/// and skips the hooks after it
+#[async_trait]
+pub trait SessionHook: Send + Sync {
+    /// connection accepted, before TLS and protocol detection
+    async fn on_accept(&self, _client: &ClientInfo) -> Result<(), ProxyError> {
+        Ok(())
+    }
+
+    /// proxy request parsed and authenticated, `session` has its method and target
+    async fn on_handshake(&self, _session: &SessionInfo) -> Result<(), ProxyError> {
+        Ok(())
+    }
+
+    /// address to connect to instead of `target`, before the acl check,
+    /// the next hook gets the returned address
+    async fn before_connect(
+        &self,
+        _session: &SessionInfo,
+        target: Address,
+    ) -> Result<Address, ProxyError> {
+        Ok(target)
+    }
+
+    /// connected to `target`, the address returned by `before_connect`
+    async fn on_connected(&self, _session: &SessionInfo, _target: &Address) {}
+
+    /// session closed, also for sessions aborted on shutdown, connections closed
+    /// before a protocol is detected have no session
+    async fn on_close(&self, _record: &AccessRecord) {}
+}
+
+#[async_trait]
+impl<H> SessionHook for Arc<H>
+where
+    H: SessionHook + ?Sized,
+{
+    async fn on_accept(&self, client: &ClientInfo) -> Result<(), ProxyError> {
+        (**self).on_accept(client).await
+    }
+
+    async fn on_handshake(&self, session: &SessionInfo) -> Result<(), ProxyError> {
+        (**self).on_handshake(session).await
+    }
+
+    async fn before_connect(
+        &self,
+        session: &SessionInfo,
+        target: Address,
+    ) -> Result<Address, ProxyError> {
+        (**self).before_connect(session, target).await
+    }
+
+    async fn on_connected(&self, session: &SessionInfo, target: &Address) {
+        (**self).on_connected(session, target).await
+    }
+
+    async fn on_close(&self, record: &AccessRecord) {
+        (**self).on_close(record).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio::sync::Notify;
+
+    use super::SessionHook;
+    use crate::ProxyError;
+    use crate::address::Address;
+    use crate::connector::DirectConnector;
+    use crate::server::{AccessRecord, ClientInfo, SessionInfo};
+    use crate::testing::{bind, pong_target, request, spawn};
+
+    struct Audit {
+        events: Mutex<Vec<String>>,
+        closed: Notify,
+        redirect: Address,
+    }
+
+    impl Audit {
+        fn push(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+
+        /// the client sees EOF before `on_close` runs
+        async fn wait_close(&self) {
+            tokio::time::timeout(Duration::from_secs(5), self.closed.notified())
+                .await
+                .expect("on_close not called");
+        }
+    }
+
+    #[async_trait]
+    impl SessionHook for Audit {
+        async fn on_accept(&self, _client: &ClientInfo) -> Result<(), ProxyError> {
+            self.push("accept".to_string());
+            Ok(())
+        }
+
+        async fn on_handshake(&self, session: &SessionInfo) -> Result<(), ProxyError> {
+            let target = session.target.clone().unwrap();
+            self.push(format!("{} {}", session.method.as_deref().unwrap(), target));
+            match target.host().as_str() {
+                "blocked.test" => Err(ProxyError::Denied(target)),
+                _ => Ok(()),
+            }
+        }
+
+        async fn before_connect(
+            &self,
+            _session: &SessionInfo,
+            target: Address,
+        ) -> Result<Address, ProxyError> {
+            match target.host().as_str() {
+                "redirect.test" => Ok(self.redirect.clone()),
+                _ => Ok(target),
+            }
+        }
+
+        async fn on_connected(&self, _session: &SessionInfo, target: &Address) {
+            self.push(format!("connected {}", target.port()));
+        }
+
+        async fn on_close(&self, record: &AccessRecord) {
+            self.push(format!("close {} {}", record.close, record.bytes_down));
+            self.closed.notify_one();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let target_addr = pong_target().await;
+        let audit = Arc::new(Audit {
+            events: Mutex::new(Vec::new()),
+            closed: Notify::new(),
+            redirect: Address::Sock(target_addr),
+        });
+        let proxy = spawn(bind(DirectConnector).await.with_hook(audit.clone()));
+
+        let data = request(proxy, "redirect.test:443").await;
+        assert_eq!(data, b"HTTP/1.1 200 Ok\r\n\r\npong");
+        audit.wait_close().await;
+        let data = request(proxy, "blocked.test:443").await;
+        assert!(data.starts_with(b"HTTP/1.1 403"));
+        audit.wait_close().await;
+
+        let events = audit.events.lock().unwrap().clone();
+        let port = target_addr.port();
+        assert_eq!(
+            events,
+            [
+                "accept".to_string(),
+                "CONNECT redirect.test:443".to_string(),
+                format!("connected {port}"),
+                "close ok 4".to_string(),
+                "accept".to_string(),
+                "CONNECT blocked.test:443".to_string(),
+                "close denied 0".to_string(),
+            ]
+        );
+    }
+}
@@ -1,15 +1,27 @@
 use std::net::SocketAddr;
+#[cfg(feature = "mitm")]
+use std::sync::Arc;
 
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use bytes::Bytes;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use url::{Host, Url};
 
 use crate::address::Address;
+#[cfg(feature = "mitm")]
+use crate::server::Mitm;
+use crate::server::{Detect, ProtocolHandler, Session};
+use crate::transport::BoxedTransport;
 use crate::util::{BufIoExt, DuplexCopy};
 use crate::{ProxyError, connector::Connector};
 
+const MAX_METHOD_LEN: usize = 16;
+
 pub struct HttpHandle {
-    //
+    #[cfg(feature = "mitm")]
+    mitm: Option<Arc<Mitm>>,
 }
 
 impl Default for HttpHandle {
@@ -20,51 +32,187 @@ impl Default for HttpHandle {
 
 impl HttpHandle {
     pub fn new() -> Self {
-        HttpHandle {}
+        HttpHandle {
+            #[cfg(feature = "mitm")]
+            mitm: None,
+        }
     }
 
-    pub async fn handle<T, C>(&self, connector: &C, mut io: BufReader<T>) -> Result<(), ProxyError>
+    /// intercept TLS inside CONNECT tunnels
+    #[cfg(feature = "mitm")]
+    pub fn with_mitm(mut self, mitm: Mitm) -> Self {
+        self.mitm = Some(Arc::new(mitm));
+        self
+    }
+
+    pub async fn handle<T, C>(
+        &self,
+        session: &Session<'_, C>,
+        mut io: BufReader<T>,
+    ) -> Result<(), ProxyError>
     where
         T: AsyncRead + AsyncWrite + Unpin,
-        C: Connector,
+        C: Connector + Sync,
         <C as Connector>::Transport: Unpin,
     {
-        let head_line = io
-            .read_until_bytes(b"\r\n")
-            .await
-            .map_err(|e| io_fail!(e, "read http head line"))?;
+        let read_head = async {
+            io.read_until_bytes(b"\r\n")
+                .await
+                .map_err(|e| io_fail!(e, "read http head line"))
+        };
+        let head_line = match session.handshake(read_head).await {
+            Ok(line) => line,
+            Err(e) => return Err(reply_error(&mut io, e).await),
+        };
         let request = Request::parse(&head_line)?;
-        let mut remote = connector
-            .connect_tcp(&request.addr)
-            .await
-            .map_err(|e| connect_remote_fail!(request.addr.clone(), "{}", e))?;
+        session.set_method(request.method);
+        debug!("{} {} {}", session.client(), request.method, request.addr);
+        let connect = request.method == "CONNECT";
+        // header lines forwarded with a plain request, left in the buffer unless read for auth
+        let mut headers = Vec::new();
+        let auth_required = session.auth_required();
+        if connect || auth_required {
+            let read_headers = async {
+                let mut authorization = None;
+                loop {
+                    let line = io.read_until_bytes(b"\r\n").await?;
+                    let end = line.len() <= 2;
+                    match proxy_authorization(&line) {
+                        Some(value) => authorization = Some(value),
+                        None if !connect => headers.extend_from_slice(&line),
+                        None => {}
+                    }
+                    if end {
+                        return Ok::<_, ProxyError>(authorization);
+                    }
+                }
+            };
+            let authorization = match session.handshake(read_headers).await {
+                Ok(authorization) => authorization,
+                Err(e) => return Err(reply_error(&mut io, e).await),
+            };
+            if auth_required {
+                let result = match authorization.as_deref().and_then(parse_basic) {
+                    Some((user, password)) => session.authenticate(&user, &password),
+                    None => Err(ProxyError::Unauthorized),
+                };
+                if let Err(e) = result {
+                    return Err(reply_error(&mut io, e).await);
+                }
+            }
+        }
+        let mut remote = match session.connect(&request.addr).await {
+            Ok(remote) => remote,
+            Err(e) => return Err(reply_error(&mut io, e).await),
+        };
 
-        if request.method == "CONNECT" {
-            while io.read_until_bytes(b"\r\n").await?.len() > 2 {}
+        if connect {
             io.write_all(b"HTTP/1.1 200 Ok\r\n\r\n").await?;
+            #[cfg(feature = "mitm")]
+            if let Some(mitm) = &self.mitm {
+                return mitm.relay(session, io, remote, &request.addr).await;
+            }
         } else {
             let line = request
                 .build_http_line()
                 .ok_or_else(|| format_err!("missing http url"))?;
             remote.write_all(line.as_bytes()).await?;
+            remote.write_all(&headers).await?;
         }
         let buffer = io.buffer();
         if !buffer.is_empty() {
             remote.write_all(&Bytes::copy_from_slice(buffer)).await?;
         }
-        let _ = DuplexCopy::with_pending(
-            format!("local(to {})", request.addr),
-            io.into_inner(),
-            false,
-            format!("remote({})", request.addr),
-            remote,
-            true,
-        )
-        .await?;
+        session
+            .relay(DuplexCopy::with_pending(
+                format!("local(to {})", request.addr),
+                io.into_inner(),
+                false,
+                format!("remote({})", request.addr),
+                remote,
+                true,
+            ))
+            .await?;
         Ok(())
     }
 }
 
+/// reply the HTTP status matching the error before the connection is closed
+async fn reply_error<T>(io: &mut T, e: ProxyError) -> ProxyError
+where
+    T: AsyncWrite + Unpin,
+{
+    let status = match &e {
+        ProxyError::HandshakeTimeout => "408 Request Timeout",
+        ProxyError::Unauthorized => {
+            "407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxies\""
+        }
+        ProxyError::Denied(_) => "403 Forbidden",
+        ProxyError::ConnectTimeout(_) => "504 Gateway Timeout",
+        ProxyError::ConnectRemoteFail(..) => "502 Bad Gateway",
+        _ => return e,
+    };
+    let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    let _ = io.write_all(response.as_bytes()).await;
+    e
+}
+
+#[async_trait]
+impl<C> ProtocolHandler<C> for HttpHandle
+where
+    C: Connector + Send + Sync,
+    <C as Connector>::Transport: Unpin + Send,
+{
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn detect(&self, prefix: &[u8]) -> Detect {
+        for (i, b) in prefix.iter().take(MAX_METHOD_LEN + 1).enumerate() {
+            match b {
+                b' ' if i > 0 => return Detect::Match,
+                b'A'..=b'Z' => {}
+                _ => return Detect::NoMatch,
+            }
+        }
+        if prefix.len() > MAX_METHOD_LEN {
+            Detect::NoMatch
+        } else {
+            Detect::NeedMore
+        }
+    }
+
+    async fn handle(
+        &self,
+        session: &Session<'_, C>,
+        io: BufReader<BoxedTransport>,
+    ) -> Result<(), ProxyError> {
+        HttpHandle::handle(self, session, io).await
+    }
+}
+
+/// value of a `Proxy-Authorization` header line
+fn proxy_authorization(line: &[u8]) -> Option<String> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (name, value) = line.split_once(':')?;
+    if !name.trim().eq_ignore_ascii_case("proxy-authorization") {
+        return None;
+    }
+    Some(value.trim().to_string())
+}
+
+/// user and password of a `Basic` authorization
+fn parse_basic(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
 struct Request<'a> {
     addr: Address,
     method: &'a str,
@@ -139,3 +287,21 @@ impl<'a> Request<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::parse_basic;
+
+    #[test]
+    fn test_parse_basic() {
+        let parsed = parse_basic("Basic YWxpY2U6c2VjcmV0");
+        assert_eq!(parsed, Some(("alice".to_string(), "secret".to_string())));
+        // user "a", password "b"
+        let parsed = parse_basic("basic YTpi");
+        assert_eq!(parsed, Some(("a".to_string(), "b".to_string())));
+        assert!(parse_basic("Basic YTpi==").is_none());
+        assert!(parse_basic("Basic YT=pi").is_none());
+        assert!(parse_basic("Basic YTpiYw").is_none());
+        assert!(parse_basic("Bearer YWxpY2U6c2VjcmV0").is_none());
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::{Instant, timeout_at};
+
+/// what to do with a connection accepted over the session limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// close the connection immediately
+    #[default]
+    Reject,
+    /// wait for a free slot, close the connection if none frees up in time
+    Queue(Duration),
+}
+
+/// limits on accepted connections, `None` is unlimited
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimits {
+    /// active sessions of the server
+    pub max_sessions: Option<usize>,
+    /// active sessions of a single source IP
+    pub max_sessions_per_ip: Option<usize>,
+    pub overflow: Overflow,
+    /// new connections per second of a single source IP, bursts up to the same amount,
+    /// connections over the rate are closed without queueing
+    pub accept_rate_per_ip: Option<u32>,
+}
+
+/// number of tracked IPs before idle accept rate buckets are pruned
+const PRUNE_RATE_LEN: usize = 4096;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last = now;
+    }
+}
+
+#[derive(Default)]
+struct State {
+    active: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    rate: HashMap<IpAddr, Bucket>,
+}
+
+pub(crate) struct Limiter {
+    limits: ConnectionLimits,
+    state: Mutex<State>,
+    released: Notify,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            state: Mutex::new(State::default()),
+            released: Notify::new(),
+        }
+    }
+
+    /// take one accept token of `ip`, false if the accept rate is exceeded
+    pub(crate) fn check_rate(&self, ip: IpAddr) -> bool {
+        let Some(rate) = self.limits.accept_rate_per_ip else {
+            return true;
+        };
+        let rate = rate as f64;
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if state.rate.len() >= PRUNE_RATE_LEN {
+            state.rate.retain(|_, b| {
+                b.refill(rate, now);
+                b.tokens < rate
+            });
+        }
+        let bucket = state.rate.entry(ip).or_insert(Bucket {
+            tokens: rate,
+            last: now,
+        });
+        bucket.refill(rate, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// take a session slot of `ip`, waiting for one if the overflow policy queues,
+    /// `None` if the connection should be closed
+    pub(crate) async fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<SessionPermit> {
+        let deadline = match self.limits.overflow {
+            Overflow::Reject => return self.try_acquire(ip),
+            Overflow::Queue(wait) => Instant::now() + wait,
+        };
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if let Some(permit) = self.try_acquire(ip) {
+                return Some(permit);
+            }
+            timeout_at(deadline, released).await.ok()?;
+        }
+    }
+
+    fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<SessionPermit> {
+        let mut state = self.state.lock().unwrap();
+        if self
+            .limits
+            .max_sessions
+            .is_some_and(|max| state.active >= max)
+        {
+            return None;
+        }
+        let per_ip = state.per_ip.get(&ip).copied().unwrap_or(0);
+        if self
+            .limits
+            .max_sessions_per_ip
+            .is_some_and(|max| per_ip >= max)
+        {
+            return None;
+        }
+        state.active += 1;
+        state.per_ip.insert(ip, per_ip + 1);
+        Some(SessionPermit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.active -= 1;
+        if let Some(n) = state.per_ip.get_mut(&ip) {
+            *n -= 1;
+            if *n == 0 {
+                state.per_ip.remove(&ip);
+            }
+        }
+        drop(state);
+        self.released.notify_waiters();
+    }
+}
+
+/// session slot, released on drop
+pub(crate) struct SessionPermit {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{ConnectionLimits, Overflow};
+    use crate::connector::DirectConnector;
+    use crate::testing::{self, bind, spawn};
+
+    async fn serve(limits: ConnectionLimits) -> SocketAddr {
+        spawn(bind(DirectConnector).await.with_limits(limits))
+    }
+
+    async fn open_tunnel(proxy: SocketAddr, target: &TcpListener) -> Option<TcpStream> {
+        testing::open_tunnel(proxy, target.local_addr().unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn test_reject_over_limit() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = serve(ConnectionLimits {
+            max_sessions_per_ip: Some(1),
+            ..Default::default()
+        })
+        .await;
+
+        let first = open_tunnel(proxy, &target).await.unwrap();
+        assert!(open_tunnel(proxy, &target).await.is_none());
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(open_tunnel(proxy, &target).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_queue_over_limit() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = serve(ConnectionLimits {
+            max_sessions: Some(1),
+            overflow: Overflow::Queue(Duration::from_secs(5)),
+            ..Default::default()
+        })
+        .await;
+
+        let first = open_tunnel(proxy, &target).await.unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(first);
+        });
+        assert!(open_tunnel(proxy, &target).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_accept_rate() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = serve(ConnectionLimits {
+            accept_rate_per_ip: Some(2),
+            ..Default::default()
+        })
+        .await;
+
+        let _first = open_tunnel(proxy, &target).await.unwrap();
+        let _second = open_tunnel(proxy, &target).await.unwrap();
+        assert!(open_tunnel(proxy, &target).await.is_none());
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt::Debug;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio_stream::{Stream, StreamExt};
+
+#[cfg(feature = "tls")]
+use crate::server::TlsAcceptor;
+use crate::server::reload::{Policy, PolicySlot, Swap};
+use crate::server::{Acl, Credentials, HttpHandle, ProtocolHandler, Socks5Handle, TcpIncoming};
+use crate::transport::BoxedTransport;
+use crate::{ProxyError, connector::Connector};
+
+/// incoming stream of any transport, see `Listener::from_incoming`
+pub type BoxedIncoming =
+    Pin<Box<dyn Stream<Item = Result<(BoxedTransport, SocketAddr), Error>> + Send>>;
+
+/// settings of one listener
+pub(crate) struct Endpoint<C> {
+    /// shared with the `ReloadHandle` once the server runs
+    pub(crate) policy: PolicySlot<C>,
+    pub(crate) protocols: Vec<Box<dyn ProtocolHandler<C>>>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<TlsAcceptor>,
+}
+
+impl<C> Endpoint<C>
+where
+    C: Connector + Send + Sync,
+    <C as Connector>::Transport: Unpin + Send,
+{
+    pub(crate) fn new(connector: C) -> Self {
+        Self {
+            policy: Arc::new(Swap::new(Policy::new(connector))),
+            protocols: vec![Box::new(Socks5Handle::new()), Box::new(HttpHandle::new())],
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+impl<C> Endpoint<C> {
+    /// policy while building the server, it is not shared before `ProxyServer::run`
+    pub(crate) fn policy_mut(&mut self) -> &mut Policy<C> {
+        Arc::get_mut(&mut self.policy)
+            .and_then(Swap::get_mut)
+            .expect("policy shared before run")
+    }
+
+    pub(crate) fn add_protocol(&mut self, protocol: Box<dyn ProtocolHandler<C>>) {
+        let protocols = &mut self.protocols;
+        match protocols.iter_mut().find(|p| p.name() == protocol.name()) {
+            Some(p) => *p = protocol,
+            None => protocols.push(protocol),
+        }
+    }
+
+    pub(crate) fn remove_protocol(&mut self, name: &str) {
+        self.protocols.retain(|p| p.name() != name);
+    }
+}
+
+/// additional listener of a `ProxyServer` with its own connector, protocols, acl and TLS,
+/// sessions share the limits, metrics, registry and access log of the server
+pub struct Listener<C> {
+    pub(crate) incoming: BoxedIncoming,
+    pub(crate) endpoint: Endpoint<C>,
+}
+
+impl<C> Listener<C>
+where
+    C: Connector + Send + Sync,
+    <C as Connector>::Transport: Unpin + Send,
+{
+    pub async fn bind<A>(connector: C, addr: A) -> Result<Self, ProxyError>
+    where
+        A: ToSocketAddrs + Clone + Debug,
+    {
+        let listener = match TcpListener::bind(addr.clone()).await {
+            Ok(l) => l,
+            Err(e) => {
+                bail!("bind {:?} fail: {}", addr, e);
+            }
+        };
+        Ok(Self::from_listener(connector, listener))
+    }
+
+    pub fn from_listener(connector: C, listener: TcpListener) -> Self {
+        Self::from_incoming(connector, TcpIncoming { listener })
+    }
+
+    pub fn from_incoming<I, T>(connector: C, incoming: I) -> Self
+    where
+        I: Stream<Item = Result<(T, SocketAddr), Error>> + Send + 'static,
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let incoming = incoming.map(|r| r.map(|(t, addr)| (Box::new(t) as BoxedTransport, addr)));
+        Self {
+            incoming: Box::pin(incoming),
+            endpoint: Endpoint::new(connector),
+        }
+    }
+}
+
+impl<C> Listener<C> {
+    /// register a protocol, replacing the one with the same name,
+    /// socks5 and http are registered by default
+    pub fn with_protocol<P>(mut self, protocol: P) -> Self
+    where
+        P: ProtocolHandler<C> + 'static,
+    {
+        self.endpoint.add_protocol(Box::new(protocol));
+        self
+    }
+
+    pub fn without_protocol(mut self, name: &str) -> Self {
+        self.endpoint.remove_protocol(name);
+        self
+    }
+
+    /// check each target against `acl` before connecting
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.endpoint.policy_mut().acl = Some(acl);
+        self
+    }
+
+    /// require a username and password, clients authenticated by a TLS
+    /// certificate are accepted without
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.endpoint.policy_mut().credentials = Some(credentials);
+        self
+    }
+
+    /// replace the connector, acl and credentials
+    pub fn with_policy(mut self, policy: Policy<C>) -> Self {
+        *self.endpoint.policy_mut() = policy;
+        self
+    }
+
+    /// terminate TLS on accepted connections before protocol sniffing
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.endpoint.tls = Some(acceptor);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::Listener;
+    use crate::connector::DirectConnector;
+    use crate::server::{Acl, Action, Metrics, ProxyServer, Rule};
+
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut sock, _) = target.accept().await.unwrap();
+            sock.write_all(b"pong").await.unwrap();
+        });
+
+        let socks_only = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_addr = socks_only.local_addr().unwrap();
+        let http_only = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_addr = http_only.local_addr().unwrap();
+        let metrics = Metrics::new();
+        let acl = Acl::new(Action::Allow).with_rule(Rule::deny().with_ports(1..=1023));
+        let server = ProxyServer::from_listener(DirectConnector, socks_only)
+            .without_protocol("http")
+            .with_listener(
+                Listener::from_listener(DirectConnector, http_only)
+                    .without_protocol("socks5")
+                    .with_acl(acl),
+            )
+            .with_metrics(metrics.clone());
+        tokio::spawn(server.run());
+
+        // http is disabled on the first listener
+        let mut sock = TcpStream::connect(socks_addr).await.unwrap();
+        sock.write_all(b"CONNECT 127.0.0.1:80 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut data = Vec::new();
+        let _ = sock.read_to_end(&mut data).await;
+        assert!(data.is_empty());
+
+        // the acl only applies to the second listener
+        let mut sock = TcpStream::connect(http_addr).await.unwrap();
+        sock.write_all(b"CONNECT 127.0.0.1:80 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        sock.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 "));
+
+        let mut sock = TcpStream::connect(socks_addr).await.unwrap();
+        let mut request = b"\x05\x01\x00\x05\x01\x00\x01\x7f\x00\x00\x01".to_vec();
+        request.extend_from_slice(&target_port.to_be_bytes());
+        sock.write_all(&request).await.unwrap();
+        let mut data = Vec::new();
+        sock.read_to_end(&mut data).await.unwrap();
+        assert_eq!(&data[data.len() - 4..], b"pong");
+
+        assert!(
+            metrics
+                .render()
+                .contains("proxy_accepted_connections_total 3\n")
+        );
+    }
+}
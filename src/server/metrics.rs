@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::ProxyError;
+use crate::util::BufIoExt;
+
+/// upper bounds of the connect latency histogram, in seconds
+const CONNECT_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// max bytes of a scrape request head
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; CONNECT_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(CONNECT_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    accepted: AtomicU64,
+    active: AtomicI64,
+    bytes_up: Arc<AtomicU64>,
+    bytes_down: Arc<AtomicU64>,
+    handshakes: Mutex<BTreeMap<(String, &'static str), u64>>,
+    connect: Mutex<BTreeMap<String, Histogram>>,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// server metrics in the Prometheus text format, cheap to clone,
+/// may be shared by several servers
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn accepted(&self) {
+        self.inner.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// count an active session until the guard is dropped
+    pub(crate) fn active(&self) -> ActiveGuard {
+        self.inner.active.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard(self.clone())
+    }
+
+    /// `outcome` is one of ok, error, timeout
+    pub(crate) fn handshake(&self, protocol: &str, outcome: &'static str) {
+        let mut handshakes = self.inner.handshakes.lock().unwrap();
+        *handshakes
+            .entry((protocol.to_string(), outcome))
+            .or_default() += 1;
+    }
+
+    pub(crate) fn connected(&self, outbound: &str, latency: Duration) {
+        let mut connect = self.inner.connect.lock().unwrap();
+        match connect.get_mut(outbound) {
+            Some(h) => h.observe(latency.as_secs_f64()),
+            None => {
+                let mut h = Histogram::default();
+                h.observe(latency.as_secs_f64());
+                connect.insert(outbound.to_string(), h);
+            }
+        }
+    }
+
+    pub(crate) fn error(&self, e: &ProxyError) {
+        let mut errors = self.inner.errors.lock().unwrap();
+        *errors.entry(e.kind_name()).or_default() += 1;
+    }
+
+    /// counters of bytes from clients and from targets
+    pub(crate) fn byte_counters(&self) -> (Arc<AtomicU64>, Arc<AtomicU64>) {
+        (self.inner.bytes_up.clone(), self.inner.bytes_down.clone())
+    }
+
+    /// render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let inner = &self.inner;
+        let mut out = String::new();
+        header(
+            &mut out,
+            "proxy_accepted_connections_total",
+            "counter",
+            "Accepted client connections.",
+        );
+        let accepted = inner.accepted.load(Ordering::Relaxed);
+        let _ = writeln!(out, "proxy_accepted_connections_total {accepted}");
+
+        header(
+            &mut out,
+            "proxy_active_sessions",
+            "gauge",
+            "Sessions being served.",
+        );
+        let active = inner.active.load(Ordering::Relaxed);
+        let _ = writeln!(out, "proxy_active_sessions {active}");
+
+        header(
+            &mut out,
+            "proxy_handshakes_total",
+            "counter",
+            "Protocol handshakes by protocol and outcome.",
+        );
+        for ((protocol, outcome), n) in inner.handshakes.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "proxy_handshakes_total{{protocol=\"{}\",outcome=\"{}\"}} {}",
+                escape(protocol),
+                outcome,
+                n
+            );
+        }
+
+        header(
+            &mut out,
+            "proxy_connect_duration_seconds",
+            "histogram",
+            "Latency of successful connects to targets by outbound.",
+        );
+        for (outbound, h) in inner.connect.lock().unwrap().iter() {
+            let outbound = escape(outbound);
+            for (n, bound) in h.buckets.iter().zip(CONNECT_BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "proxy_connect_duration_seconds_bucket{{outbound=\"{outbound}\",le=\"{bound}\"}} {n}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "proxy_connect_duration_seconds_bucket{{outbound=\"{outbound}\",le=\"+Inf\"}} {}",
+                h.count
+            );
+            let _ = writeln!(
+                out,
+                "proxy_connect_duration_seconds_sum{{outbound=\"{outbound}\"}} {}",
+                h.sum
+            );
+            let _ = writeln!(
+                out,
+                "proxy_connect_duration_seconds_count{{outbound=\"{outbound}\"}} {}",
+                h.count
+            );
+        }
+
+        header(
+            &mut out,
+            "proxy_transferred_bytes_total",
+            "counter",
+            "Relayed bytes by direction.",
+        );
+        let up = inner.bytes_up.load(Ordering::Relaxed);
+        let down = inner.bytes_down.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "proxy_transferred_bytes_total{{direction=\"up\"}} {up}"
+        );
+        let _ = writeln!(
+            out,
+            "proxy_transferred_bytes_total{{direction=\"down\"}} {down}"
+        );
+
+        header(
+            &mut out,
+            "proxy_errors_total",
+            "counter",
+            "Failed sessions by error kind.",
+        );
+        for (kind, n) in inner.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "proxy_errors_total{{kind=\"{kind}\"}} {n}");
+        }
+        out
+    }
+
+    /// serve `GET /metrics` on `listener` until it fails
+    pub async fn serve(self, listener: TcpListener) -> Result<(), ProxyError> {
+        loop {
+            let (sock, addr) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => return Err(io_fail!(e, "accept metrics listener")),
+            };
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = metrics.scrape(sock).await {
+                    debug!("metrics scrape from {} fail: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn scrape(&self, sock: TcpStream) -> Result<(), ProxyError> {
+        let mut io = BufReader::new(sock);
+        let head_line = io.read_until_bytes(b"\r\n").await?;
+        let mut len = head_line.len();
+        while len < MAX_REQUEST_LEN {
+            let line = io.read_until_bytes(b"\r\n").await?;
+            if line.len() <= 2 {
+                break;
+            }
+            len += line.len();
+        }
+        let mut parts = head_line.split(|b| *b == b' ');
+        let (method, path) = (parts.next(), parts.next());
+        let response = match (method, path) {
+            (Some(b"GET"), Some(b"/metrics")) => {
+                let body = self.render();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            }
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        };
+        io.write_all(response.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub(crate) struct ActiveGuard(Metrics);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.inner.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::Metrics;
+    use crate::connector::DirectConnector;
+    use crate::testing::{bind, pong_target, request, spawn};
+
+    async fn scrape(addr: std::net::SocketAddr) -> String {
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        sock.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        sock.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let target_addr = pong_target().await;
+        let metrics = Metrics::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics_addr = listener.local_addr().unwrap();
+        tokio::spawn(metrics.clone().serve(listener));
+
+        let proxy = spawn(bind(DirectConnector).await.with_metrics(metrics));
+
+        let mut data = request(proxy, target_addr).await;
+        assert_eq!(data, b"HTTP/1.1 200 Ok\r\n\r\npong");
+        let mut sock = TcpStream::connect(proxy).await.unwrap();
+        sock.write_all(b"\x04\x01").await.unwrap();
+        let _ = sock.read_to_end(&mut data).await;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let response = scrape(metrics_addr).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert!(body.contains("proxy_accepted_connections_total 2\n"));
+        assert!(body.contains("proxy_active_sessions 0\n"));
+        assert!(body.contains("proxy_handshakes_total{protocol=\"http\",outcome=\"ok\"} 1\n"));
+        assert!(body.contains("proxy_connect_duration_seconds_count{outbound=\"direct\"} 1\n"));
+        assert!(body.contains("proxy_transferred_bytes_total{direction=\"down\"} 4\n"));
+        assert!(body.contains("proxy_errors_total{kind=\"protocol_fail\"} 1\n"));
+    }
+}
@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+use rcgen::{CertificateParams, DnType, KeyPair};
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{Acceptor, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_rustls::{LazyConfigAcceptor, TlsConnector};
+
+use crate::ProxyError;
+use crate::address::Address;
+use crate::server::protocol::Rewind;
+use crate::server::{DomainPattern, Session};
+use crate::util::DuplexCopy;
+use crate::util::tls::{load_certs, provider};
+
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// content type of a TLS handshake record
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// observes decrypted traffic of intercepted CONNECT tunnels
+pub trait MitmInspector: Send + Sync {
+    /// decrypted bytes sent by the client to `host`
+    fn on_request(&self, _host: &str, _data: &[u8]) {}
+
+    /// decrypted bytes sent by `host` to the client
+    fn on_response(&self, _host: &str, _data: &[u8]) {}
+}
+
+/// TLS interception for CONNECT tunnels, leaf certificates are generated per SNI
+/// and signed by a local CA
+pub struct Mitm {
+    ca_cert: rcgen::Certificate,
+    ca_der: CertificateDer<'static>,
+    ca_key: KeyPair,
+    leaf_key: KeyPair,
+    cache: Mutex<LeafCache>,
+    bypass: Vec<DomainPattern>,
+    upstream: TlsConnector,
+    inspector: Option<Arc<dyn MitmInspector>>,
+}
+
+impl Mitm {
+    /// load the signing CA certificate and key from PEM files
+    pub fn from_ca_pem_files<P, K>(cert_path: P, key_path: K) -> Result<Self, ProxyError>
+    where
+        P: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let (cert_path, key_path) = (cert_path.as_ref(), key_path.as_ref());
+        let ca_der = load_certs(cert_path)?.remove(0);
+        let key_pem = std::fs::read_to_string(key_path)
+            .map_err(|e| io_fail!(e, "read {}", key_path.display()))?;
+        let ca_key = KeyPair::from_pem(&key_pem)
+            .map_err(|e| format_err!("invalid ca key {}: {}", key_path.display(), e))?;
+        let ca_cert = CertificateParams::from_ca_cert_der(&ca_der)
+            .and_then(|params| params.self_signed(&ca_key))
+            .map_err(|e| format_err!("invalid ca certificate {}: {}", cert_path.display(), e))?;
+        let leaf_key =
+            KeyPair::generate().map_err(|e| format_err!("generate leaf key fail: {}", e))?;
+
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let mut upstream = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format_err!("build tls config fail: {}", e))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        upstream.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Self {
+            ca_cert,
+            ca_der,
+            ca_key,
+            leaf_key,
+            cache: Mutex::new(LeafCache::new(DEFAULT_CACHE_CAPACITY)),
+            bypass: Vec::new(),
+            upstream: Arc::new(upstream).into(),
+            inspector: None,
+        })
+    }
+
+    /// tunnel these domains without interception, `*.example.com` matches subdomains
+    pub fn with_bypass<S: ToString>(mut self, domains: impl IntoIterator<Item = S>) -> Self {
+        self.bypass = domains
+            .into_iter()
+            .map(|d| DomainPattern::new(&d.to_string()))
+            .collect();
+        self
+    }
+
+    /// TLS config to re-originate connections to targets, webpki roots are trusted by default
+    pub fn with_upstream_config(mut self, config: Arc<ClientConfig>) -> Self {
+        self.upstream = config.into();
+        self
+    }
+
+    pub fn with_inspector<I: MitmInspector + 'static>(mut self, inspector: I) -> Self {
+        self.inspector = Some(Arc::new(inspector));
+        self
+    }
+
+    /// max number of generated certificates kept in cache
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache.get_mut().unwrap().capacity = capacity;
+        self
+    }
+
+    /// `host` is the SNI, or the CONNECT host without SNI
+    fn intercept(&self, host: &str) -> bool {
+        !self.bypass.iter().any(|pattern| pattern.matches(host))
+    }
+
+    /// relay a CONNECT tunnel, TLS to hosts not bypassed is intercepted,
+    /// other data is relayed as is
+    pub(crate) async fn relay<C, L, R>(
+        &self,
+        session: &Session<'_, C>,
+        mut local: L,
+        mut remote: R,
+        addr: &Address,
+    ) -> Result<(), ProxyError>
+    where
+        L: AsyncRead + AsyncWrite + Unpin,
+        R: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut hello = Vec::new();
+        let mut buf = [0; 4096];
+        // servers speaking first, e.g. SMTP or SSH, are relayed without waiting
+        // for a client hello, as are clients silent until the idle timeout
+        let first = async {
+            tokio::select! {
+                sni = read_client_hello(&mut local, &mut hello) => First::Client(sni),
+                n = remote.read(&mut buf) => First::Server(n),
+            }
+        };
+        let first = match session.idle_timeout() {
+            Some(idle) => tokio::time::timeout(idle, first)
+                .await
+                .unwrap_or(First::Server(Ok(0))),
+            None => first.await,
+        };
+        let (sni, banner) = match first {
+            First::Client(sni) => {
+                let sni = sni.map_err(|e| io_fail!(e, "read client hello to {}", addr))?;
+                (sni, &buf[..0])
+            }
+            First::Server(n) => {
+                let n = n.map_err(|e| io_fail!(e, "read from {}", addr))?;
+                (None, &buf[..n])
+            }
+        };
+        // the bypass applies to the SNI, the CONNECT host may be an ip
+        let host = sni.map(|sni| sni.unwrap_or_else(|| addr.host()));
+        let Some(host) = host.filter(|host| self.intercept(host)) else {
+            debug!("relay {} without interception", addr);
+            remote.write_all(&hello).await?;
+            local.write_all(banner).await?;
+            session
+                .relay(DuplexCopy::with_pending(
+                    format!("local(to {addr})"),
+                    local,
+                    true,
+                    format!("remote({addr})"),
+                    remote,
+                    true,
+                ))
+                .await?;
+            return Ok(());
+        };
+        let start = LazyConfigAcceptor::new(Acceptor::default(), Rewind::new(hello, local))
+            .await
+            .map_err(|e| io_fail!(e, "read client hello to {}", addr))?;
+        let local = start
+            .into_stream(self.server_config(&host)?)
+            .await
+            .map_err(|e| io_fail!(e, "tls handshake with client to {}", addr))?;
+        let server_name = ServerName::try_from(host.clone())
+            .map_err(|_| invalid_data!("invalid server name: {}", host))?;
+        let remote = self
+            .upstream
+            .connect(server_name, remote)
+            .await
+            .map_err(|e| io_fail!(e, "tls handshake with {}", addr))?;
+        debug!("intercept tls to {}", addr);
+
+        let inspector = self.inspector.as_deref();
+        let local = Inspect::new(local, |data: &[u8]| {
+            if let Some(inspector) = inspector {
+                inspector.on_request(&host, data);
+            }
+        });
+        let remote = Inspect::new(remote, |data: &[u8]| {
+            if let Some(inspector) = inspector {
+                inspector.on_response(&host, data);
+            }
+        });
+        session
+            .relay(DuplexCopy::with_pending(
+                format!("local(to {addr}, intercepted)"),
+                local,
+                true,
+                format!("remote({addr}, intercepted)"),
+                remote,
+                true,
+            ))
+            .await?;
+        Ok(())
+    }
+
+    /// cached config for `host`, generated under the cache lock so concurrent
+    /// tunnels to a new host share one certificate
+    fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>, ProxyError> {
+        let host = host.to_ascii_lowercase();
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(config) = cache.get(&host) {
+            return Ok(config);
+        }
+        let certified = self.generate(&host)?;
+        let mut config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format_err!("build tls config fail: {}", e))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(LeafCert(Arc::new(certified))));
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let config = Arc::new(config);
+        cache.insert(host, config.clone());
+        Ok(config)
+    }
+
+    fn generate(&self, host: &str) -> Result<CertifiedKey, ProxyError> {
+        let mut params = CertificateParams::new(vec![host.to_string()])
+            .map_err(|e| format_err!("invalid host {}: {}", host, e))?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        let cert = params
+            .signed_by(&self.leaf_key, &self.ca_cert, &self.ca_key)
+            .map_err(|e| format_err!("sign certificate for {} fail: {}", host, e))?;
+        let key = PrivateKeyDer::Pkcs8(self.leaf_key.serialize_der().into());
+        let key = any_supported_type(&key).map_err(|e| format_err!("invalid leaf key: {}", e))?;
+        Ok(CertifiedKey::new(
+            vec![cert.der().clone(), self.ca_der.clone()],
+            key,
+        ))
+    }
+}
+
+/// generated configs by host, the least recently used is evicted when full
+struct LeafCache {
+    entries: HashMap<String, (Arc<ServerConfig>, u64)>,
+    capacity: usize,
+    /// incremented on each use
+    clock: u64,
+}
+
+impl LeafCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, host: &str) -> Option<Arc<ServerConfig>> {
+        self.clock += 1;
+        let (config, last_used) = self.entries.get_mut(host)?;
+        *last_used = self.clock;
+        Some(config.clone())
+    }
+
+    fn insert(&mut self, host: String, config: Arc<ServerConfig>) {
+        while !self.entries.is_empty() && self.entries.len() >= self.capacity {
+            let lru = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(host, _)| host.clone())
+                .unwrap();
+            self.entries.remove(&lru);
+        }
+        self.clock += 1;
+        self.entries.insert(host, (config, self.clock));
+    }
+}
+
+/// side of a CONNECT tunnel sending first
+enum First {
+    /// result of `read_client_hello`
+    Client(Result<Option<Option<String>>, Error>),
+    /// bytes read from the server into the banner buffer
+    Server(Result<usize, Error>),
+}
+
+/// read the client hello into `hello` without handling it, `None` if the
+/// tunnel does not start with a TLS handshake record, else the SNI if any
+async fn read_client_hello<L>(
+    local: &mut L,
+    hello: &mut Vec<u8>,
+) -> Result<Option<Option<String>>, Error>
+where
+    L: AsyncRead + Unpin,
+{
+    let mut acceptor = Acceptor::default();
+    let mut buf = [0; 4096];
+    loop {
+        let n = local.read(&mut buf).await?;
+        if n == 0 {
+            if hello.is_empty() {
+                return Ok(None);
+            }
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+        hello.extend_from_slice(&buf[..n]);
+        if hello[0] != TLS_HANDSHAKE {
+            return Ok(None);
+        }
+        acceptor.read_tls(&mut &buf[..n])?;
+        match acceptor.accept() {
+            Ok(Some(accepted)) => {
+                let sni = accepted.client_hello().server_name().map(str::to_string);
+                return Ok(Some(sni));
+            }
+            Ok(None) => {}
+            Err((e, _)) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LeafCert(Arc<CertifiedKey>);
+
+impl ResolvesServerCert for LeafCert {
+    fn resolve(&self, _: rustls::server::ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+pin_project! {
+    struct Inspect<T, F> {
+        #[pin]
+        inner: T,
+        on_read: F,
+    }
+}
+
+impl<T, F> Inspect<T, F> {
+    fn new(inner: T, on_read: F) -> Self {
+        Self { inner, on_read }
+    }
+}
+
+impl<T, F> AsyncRead for Inspect<T, F>
+where
+    T: AsyncRead,
+    F: FnMut(&[u8]),
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        if buf.filled().len() > filled {
+            (this.on_read)(&buf.filled()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T, F> AsyncWrite for Inspect<T, F>
+where
+    T: AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsConnector;
+
+    use super::{Mitm, MitmInspector};
+    use crate::connector::DirectConnector;
+    use crate::server::{HttpHandle, TlsAcceptor};
+    use crate::testing::{TempDir, bind, open_tunnel, self_signed, spawn, write_pem};
+    use crate::util::BufIoExt;
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+    impl MitmInspector for Recorder {
+        fn on_request(&self, host: &str, data: &[u8]) {
+            assert_eq!(host, "localhost");
+            self.0.lock().unwrap().extend_from_slice(data);
+        }
+    }
+
+    fn client_config(roots: RootCertStore) -> Arc<ClientConfig> {
+        Arc::new(
+            ClientConfig::builder_with_provider(crate::util::tls::provider())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_intercept_connect() {
+        let dir = TempDir::new("mitm");
+        let (origin_pem, origin) = self_signed(&dir, "origin", &["localhost"]);
+        let acceptor = TlsAcceptor::from_pem_files(&origin_pem.cert, &origin_pem.key).unwrap();
+        let origin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin_listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (sock, _) = origin_listener.accept().await.unwrap();
+            let mut sock = BufReader::new(acceptor.accept(sock).await.unwrap());
+            while sock.read_until_bytes(b"\r\n").await.unwrap().len() > 2 {}
+            sock.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+            sock.shutdown().await.unwrap();
+        });
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let ca_pem = write_pem(&dir, "ca", &ca, &ca_key);
+
+        let mut origin_roots = RootCertStore::empty();
+        origin_roots.add(origin.cert.der().clone()).unwrap();
+        let recorder = Recorder::default();
+        let mitm = Mitm::from_ca_pem_files(&ca_pem.cert, &ca_pem.key)
+            .unwrap()
+            .with_upstream_config(client_config(origin_roots))
+            .with_inspector(recorder.clone());
+        let server = bind(DirectConnector).await;
+        let proxy = spawn(server.with_protocol(HttpHandle::new().with_mitm(mitm)));
+        let target = format!("localhost:{origin_port}");
+        let sock = open_tunnel(proxy, target).await.unwrap();
+
+        let mut ca_roots = RootCertStore::empty();
+        ca_roots.add(ca.der().clone()).unwrap();
+        let connector = TlsConnector::from(client_config(ca_roots));
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut tls = connector.connect(server_name, sock).await.unwrap();
+        tls.write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tls.read_to_end(&mut response).await.unwrap();
+        assert!(response.ends_with(b"\r\n\r\nok"));
+        assert!(
+            recorder
+                .0
+                .lock()
+                .unwrap()
+                .starts_with(b"GET / HTTP/1.1\r\n")
+        );
+    }
+
+    #[test]
+    fn test_bypass() {
+        let dir = TempDir::new("mitm-bypass");
+        let (ca, _) = self_signed(&dir, "ca", &[]);
+        let mitm = Mitm::from_ca_pem_files(&ca.cert, &ca.key)
+            .unwrap()
+            .with_bypass(["bank.com", "*.apple.com"]);
+        assert!(!mitm.intercept("bank.com"));
+        assert!(mitm.intercept("www.bank.com"));
+        assert!(!mitm.intercept("api.Apple.com"));
+        assert!(mitm.intercept("apple.com"));
+
+        // least recently used certificates are evicted
+        let mitm = mitm.with_cache_capacity(2);
+        let a = mitm.server_config("a.test").unwrap();
+        mitm.server_config("b.test").unwrap();
+        assert!(Arc::ptr_eq(&a, &mitm.server_config("A.test").unwrap()));
+        mitm.server_config("c.test").unwrap();
+        let cache = mitm.cache.lock().unwrap();
+        let mut hosts: Vec<_> = cache.entries.keys().cloned().collect();
+        hosts.sort();
+        assert_eq!(hosts, ["a.test", "c.test"]);
+    }
+
+    #[tokio::test]
+    async fn test_passthrough() {
+        let dir = TempDir::new("mitm-pass");
+        let (origin_pem, origin) = self_signed(&dir, "origin", &["localhost"]);
+        let acceptor = TlsAcceptor::from_pem_files(&origin_pem.cert, &origin_pem.key).unwrap();
+
+        // plain echo first, then a TLS echo
+        let origin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = origin_listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            sock.read_exact(&mut buf).await.unwrap();
+            sock.write_all(&buf).await.unwrap();
+            let (sock, _) = origin_listener.accept().await.unwrap();
+            let mut sock = acceptor.accept(sock).await.unwrap();
+            sock.read_exact(&mut buf).await.unwrap();
+            sock.write_all(&buf).await.unwrap();
+            sock.shutdown().await.unwrap();
+        });
+
+        // the origin certificate is the CA, so an intercepted tunnel fails verification
+        let mitm = Mitm::from_ca_pem_files(&origin_pem.cert, &origin_pem.key)
+            .unwrap()
+            .with_bypass(["localhost"]);
+        let server = bind(DirectConnector).await;
+        let proxy = spawn(server.with_protocol(HttpHandle::new().with_mitm(mitm)));
+
+        let mut sock = open_tunnel(proxy, origin_addr).await.unwrap();
+        sock.write_all(b"SSH-").await.unwrap();
+        let mut buf = [0; 4];
+        sock.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"SSH-");
+
+        // bypassed by SNI although the CONNECT target is an ip
+        let mut roots = RootCertStore::empty();
+        roots.add(origin.cert.der().clone()).unwrap();
+        let connector = TlsConnector::from(client_config(roots));
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let sock = open_tunnel(proxy, origin_addr).await.unwrap();
+        let mut tls = connector.connect(server_name, sock).await.unwrap();
+        tls.write_all(b"ping").await.unwrap();
+        tls.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_server_first() {
+        let dir = TempDir::new("mitm-banner");
+        let (ca, _) = self_signed(&dir, "ca", &[]);
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = origin.accept().await.unwrap();
+            sock.write_all(b"220 ready\r\n").await.unwrap();
+            let mut buf = [0; 6];
+            sock.read_exact(&mut buf).await.unwrap();
+            sock.write_all(b"221 bye\r\n").await.unwrap();
+        });
+
+        let mitm = Mitm::from_ca_pem_files(&ca.cert, &ca.key).unwrap();
+        let server = bind(DirectConnector).await;
+        let proxy = spawn(server.with_protocol(HttpHandle::new().with_mitm(mitm)));
+        let mut sock = open_tunnel(proxy, origin_addr).await.unwrap();
+        let mut banner = [0; 11];
+        sock.read_exact(&mut banner).await.unwrap();
+        assert_eq!(&banner, b"220 ready\r\n");
+        sock.write_all(b"QUIT\r\n").await.unwrap();
+        let mut reply = Vec::new();
+        sock.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"221 bye\r\n");
+    }
+}
@@ -1,28 +1,109 @@
+mod accept;
+mod access_log;
+mod acl;
+#[cfg(unix)]
+mod activation;
+mod admin;
+mod auth;
+mod bandwidth;
+#[cfg(unix)]
+mod handoff;
+mod hook;
 mod http;
+mod limit;
+mod listener;
+mod metrics;
+#[cfg(feature = "mitm")]
+mod mitm;
+mod protocol;
+mod registry;
+mod reload;
+#[cfg(unix)]
+mod reuseport;
+mod rewrite;
+mod session;
+mod shutdown;
 mod socks5;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(unix)]
+mod unix;
 
 use std::io::Error;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
+pub use accept::{AcceptErrorKind, AcceptPolicy};
+pub use access_log::{AccessLogSink, AccessRecord, JsonLinesFile};
+pub(crate) use acl::matches_target;
+pub use acl::{Acl, Action, DomainPattern, Rule};
+#[cfg(unix)]
+pub use activation::{InheritedListener, ListenFds};
+pub use admin::Admin;
+pub use auth::Credentials;
+pub use bandwidth::{Bandwidth, BandwidthLimit, BandwidthLimits};
+#[cfg(unix)]
+pub use handoff::{Handoff, HandoffReceiver};
+pub use hook::SessionHook;
 pub use http::HttpHandle;
+pub use limit::{ConnectionLimits, Overflow};
+pub use listener::{BoxedIncoming, Listener};
+pub use metrics::Metrics;
+#[cfg(feature = "mitm")]
+pub use mitm::{Mitm, MitmInspector};
+pub use protocol::{Detect, ProtocolHandler};
+pub use registry::{SessionInfo, SessionRegistry};
+pub use reload::{Policy, ReloadHandle};
+#[cfg(unix)]
+pub use reuseport::ReusePortIncoming;
+pub use rewrite::{Rewrite, RewriteRule};
+pub use session::{Session, Timeouts};
+pub use shutdown::{ShutdownHandle, ShutdownSummary};
 pub use socks5::Socks5Handle;
+#[cfg(feature = "tls")]
+pub use tls::{CertIdentity, TlsAcceptor};
+#[cfg(unix)]
+pub use unix::{UNIX_CLIENT_ADDR, UnixIncoming};
 
-use std::fmt::Debug;
-use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use futures::stream::SelectAll;
+use std::fmt::{self, Debug};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep_until};
 use tokio_stream::{Stream, StreamExt};
 
-use crate::{ProxyError, connector::Connector, util::BufIoExt};
+use crate::transport::BoxedTransport;
+use crate::{ProxyError, connector::Connector};
+use accept::Backoff;
+use bandwidth::Shaper;
+use limit::Limiter;
+use listener::Endpoint;
+use protocol::Rewind;
+use session::{handshake_outcome, with_deadline};
+
+/// max bytes read to detect the protocol
+const MAX_DETECT_LEN: usize = 1024;
 
 pub struct ProxyServer<C, I = TcpIncoming> {
     incoming: I,
-    client_handle: Arc<ClientHandle<C>>,
+    endpoint: Endpoint<C>,
+    listeners: Vec<Listener<C>>,
+    shared: Shared,
+    shutdown: ShutdownHandle,
+    accept_policy: AcceptPolicy,
+    limits: ConnectionLimits,
+    reload: ReloadHandle<C>,
 }
 
-impl<C> ProxyServer<C, TcpIncoming> {
+impl<C> ProxyServer<C, TcpIncoming>
+where
+    C: Connector + Send + Sync,
+    <C as Connector>::Transport: Unpin + Send,
+{
     pub async fn bind<A>(connector: C, addr: A) -> Result<Self, ProxyError>
     where
         A: ToSocketAddrs + Clone + Debug,
@@ -33,27 +114,165 @@ impl<C> ProxyServer<C, TcpIncoming> {
                 bail!("bind {:?} fail: {}", addr, e);
             }
         };
-        Ok(ProxyServer {
-            incoming: TcpIncoming { listener },
-            client_handle: Arc::new(ClientHandle::new(connector)),
-        })
+        Ok(Self::from_listener(connector, listener))
     }
 
     pub fn from_listener(connector: C, listener: TcpListener) -> Self {
-        ProxyServer {
-            incoming: TcpIncoming { listener },
-            client_handle: Arc::new(ClientHandle::new(connector)),
-        }
+        Self::from_incoming(connector, TcpIncoming { listener })
     }
 }
 
-impl<C, I> ProxyServer<C, I> {
+#[cfg(unix)]
+impl<C> ProxyServer<C, ReusePortIncoming>
+where
+    C: Connector + Send + Sync,
+    <C as Connector>::Transport: Unpin + Send,
+{
+    /// accept on `acceptors` `SO_REUSEPORT` listeners, see `ReusePortIncoming`
+    pub async fn bind_reuseport<A>(
+        connector: C,
+        addr: A,
+        acceptors: usize,
+    ) -> Result<Self, ProxyError>
+    where
+        A: ToSocketAddrs,
+    {
+        let incoming = ReusePortIncoming::bind(addr, acceptors).await?;
+        Ok(Self::from_incoming(connector, incoming))
+    }
+}
+
+impl<C, I> ProxyServer<C, I>
+where
+    C: Connector + Send + Sync,
+    <C as Connector>::Transport: Unpin + Send,
+{
     pub fn from_incoming(connector: C, incoming: I) -> Self {
         Self {
             incoming,
-            client_handle: Arc::new(ClientHandle::new(connector)),
+            endpoint: Endpoint::new(connector),
+            listeners: Vec::new(),
+            shared: Shared::default(),
+            shutdown: ShutdownHandle::new(),
+            accept_policy: AcceptPolicy::default(),
+            limits: ConnectionLimits::default(),
+            reload: ReloadHandle::new(),
         }
     }
+
+    /// accept on `listener` too, it keeps its own protocols, acl, TLS and connector
+    pub fn with_listener(mut self, listener: Listener<C>) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.shared.timeouts = timeouts;
+        self
+    }
+
+    /// limit active sessions and the accept rate, unlimited by default
+    pub fn with_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// shape relayed data, unlimited by default
+    pub fn with_bandwidth(mut self, limits: BandwidthLimits) -> Self {
+        self.shared.shaper = Shaper::new(limits);
+        self
+    }
+
+    pub fn with_accept_policy(mut self, policy: AcceptPolicy) -> Self {
+        self.accept_policy = policy;
+        self
+    }
+
+    /// record metrics of this server into `metrics`, see `Metrics::serve`
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.shared.metrics = metrics;
+        self
+    }
+
+    /// check each target against `acl` before connecting,
+    /// denied requests are refused by the protocol handle
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.endpoint.policy_mut().acl = Some(acl);
+        self
+    }
+
+    /// require a username and password, clients authenticated by a TLS
+    /// certificate are accepted without
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.endpoint.policy_mut().credentials = Some(credentials);
+        self
+    }
+
+    /// replace the connector, acl and credentials of the primary listener
+    pub fn with_policy(mut self, policy: Policy<C>) -> Self {
+        *self.endpoint.policy_mut() = policy;
+        self
+    }
+
+    /// emit one `AccessRecord` per session to `sink` when the session closes
+    pub fn with_access_log<S>(mut self, sink: S) -> Self
+    where
+        S: AccessLogSink + 'static,
+    {
+        self.shared.access_log = Some(Box::new(sink));
+        self
+    }
+
+    /// call `hook` on the events of each session, after the hooks added before
+    pub fn with_hook<H>(mut self, hook: H) -> Self
+    where
+        H: SessionHook + 'static,
+    {
+        self.shared.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// active sessions, can be queried while the server runs
+    pub fn registry(&self) -> SessionRegistry {
+        self.shared.registry.clone()
+    }
+
+    /// primary incoming stream, e.g. to hand off its listener, see `Handoff`
+    pub fn incoming(&self) -> &I {
+        &self.incoming
+    }
+
+    /// handle to replace the policies of all listeners while the server runs
+    pub fn reload_handle(&self) -> ReloadHandle<C> {
+        self.reload.clone()
+    }
+
+    /// handle to stop `run` and drain in-flight sessions
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// register a protocol, replacing the one with the same name,
+    /// socks5 and http are registered by default
+    pub fn with_protocol<P>(mut self, protocol: P) -> Self
+    where
+        P: ProtocolHandler<C> + 'static,
+    {
+        self.endpoint.add_protocol(Box::new(protocol));
+        self
+    }
+
+    pub fn without_protocol(mut self, name: &str) -> Self {
+        self.endpoint.remove_protocol(name);
+        self
+    }
+
+    /// terminate TLS on accepted connections before protocol sniffing
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.endpoint.tls = Some(acceptor);
+        self
+    }
 }
 
 impl<C, I, T> ProxyServer<C, I>
@@ -64,70 +283,323 @@ where
     T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
     pub fn new(connector: C, incoming: I) -> Self {
-        Self {
-            incoming,
-            client_handle: Arc::new(ClientHandle::new(connector)),
-        }
+        Self::from_incoming(connector, incoming)
     }
 
-    pub async fn run(mut self) -> Result<(), ProxyError> {
-        while let Some(result) = self.incoming.next().await {
-            match result {
-                Ok((sock, addr)) => {
-                    let client_handle = self.client_handle.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = client_handle.handle(sock, addr).await {
-                            warn!("handle {} fail: {}", addr, e);
-                        }
-                    });
+    /// accept until all incoming streams end or shutdown is requested, on shutdown
+    /// in-flight sessions are drained and the summary is returned
+    pub async fn run(self) -> Result<ShutdownSummary, ProxyError> {
+        let mut signal = self.shutdown.signal();
+        let Running {
+            mut incoming,
+            client_handle,
+            listeners: added,
+            limiter,
+            accept_policy,
+        } = self.start();
+        let mut handles = Vec::new();
+        let mut listeners = SelectAll::new();
+        for (i, (listener, handle)) in added.into_iter().enumerate() {
+            handles.push(handle);
+            listeners.push(listener.map(move |r| (i, r)));
+        }
+        let mut sessions = JoinSet::new();
+        let mut backoff = Backoff::new();
+        let mut retry_at = None;
+        let mut incoming_done = false;
+        let grace = loop {
+            let result = tokio::select! {
+                grace = signal.recv() => break grace,
+                Some(_) = sessions.join_next(), if !sessions.is_empty() => continue,
+                _ = sleep_until(retry_at.unwrap_or_else(Instant::now)), if retry_at.is_some() => {
+                    retry_at = None;
+                    continue;
                 }
-                Err(e) => {
-                    bail!("accept incoming fail: {}", e);
+                result = incoming.next(), if retry_at.is_none() && !incoming_done => match result {
+                    Some(Ok((sock, addr))) => {
+                        spawn_session(&mut sessions, &client_handle, &limiter, sock, addr);
+                        Ok(())
+                    }
+                    Some(Err(e)) => Err(e),
+                    None => {
+                        incoming_done = true;
+                        Ok(())
+                    }
+                },
+                Some((i, result)) = listeners.next(), if retry_at.is_none() && !listeners.is_empty() => {
+                    result.map(|(sock, addr)| {
+                        spawn_session(&mut sessions, &handles[i], &limiter, sock, addr);
+                    })
                 }
+            };
+            match result {
+                Ok(()) => backoff.reset(),
+                Err(e) => match backoff.on_error(&accept_policy, &e) {
+                    Ok(at) => retry_at = at,
+                    Err(e) => {
+                        sessions.detach_all();
+                        return Err(e);
+                    }
+                },
             }
-        }
-        Ok(())
+            if incoming_done && listeners.is_empty() {
+                sessions.detach_all();
+                return Ok(ShutdownSummary::default());
+            }
+        };
+
+        drop(incoming);
+        drop(listeners);
+        Ok(drain(vec![sessions], grace).await)
     }
 }
 
-struct ClientHandle<C> {
-    connector: C,
+/// server state once `run` starts, shared by the sessions of all listeners
+struct Running<C, I> {
+    incoming: I,
+    client_handle: Arc<ClientHandle<C>>,
+    listeners: Vec<(BoxedIncoming, Arc<ClientHandle<C>>)>,
+    limiter: Arc<Limiter>,
+    accept_policy: AcceptPolicy,
+}
 
-    socks5_handle: Socks5Handle,
-    http_handle: HttpHandle,
+impl<C, I> ProxyServer<C, I> {
+    /// attach the policies to the reload handle and build the handle of each listener
+    fn start(self) -> Running<C, I> {
+        let shared = Arc::new(self.shared);
+        let mut policies = vec![self.endpoint.policy.clone()];
+        policies.extend(self.listeners.iter().map(|l| l.endpoint.policy.clone()));
+        self.reload.attach(policies);
+        let client_handle = Arc::new(ClientHandle {
+            endpoint: self.endpoint,
+            shared: shared.clone(),
+        });
+        let listeners = self
+            .listeners
+            .into_iter()
+            .map(|listener| {
+                let handle = Arc::new(ClientHandle {
+                    endpoint: listener.endpoint,
+                    shared: shared.clone(),
+                });
+                (listener.incoming, handle)
+            })
+            .collect();
+        Running {
+            incoming: self.incoming,
+            client_handle,
+            listeners,
+            limiter: Arc::new(Limiter::new(self.limits)),
+            accept_policy: self.accept_policy,
+        }
+    }
 }
 
-impl<C> ClientHandle<C> {
-    fn new(connector: C) -> Self {
-        Self {
-            connector,
-            socks5_handle: Socks5Handle::new(),
-            http_handle: HttpHandle::new(),
+/// wait for `sessions` until `grace` ends, then force close the rest
+async fn drain(mut sessions: Vec<JoinSet<()>>, grace: Duration) -> ShutdownSummary {
+    let active: usize = sessions.iter().map(JoinSet::len).sum();
+    info!("shutdown, draining {} sessions in {:?}", active, grace);
+    let start = Instant::now();
+    let mut summary = ShutdownSummary::default();
+    let wait = async {
+        for set in &mut sessions {
+            while set.join_next().await.is_some() {
+                summary.drained += 1;
+            }
+        }
+    };
+    if tokio::time::timeout(grace, wait).await.is_err() {
+        for set in &mut sessions {
+            summary.aborted += set.len();
+            set.shutdown().await;
         }
     }
+    summary.elapsed = start.elapsed();
+    info!("shutdown done: {:?}", summary);
+    summary
 }
 
-impl<C> ClientHandle<C>
-where
-    C: Connector,
-    <C as Connector>::Transport: Unpin,
+fn spawn_session<C, T>(
+    sessions: &mut JoinSet<()>,
+    client_handle: &Arc<ClientHandle<C>>,
+    limiter: &Arc<Limiter>,
+    sock: T,
+    addr: SocketAddr,
+) where
+    C: Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
+    client_handle.shared.metrics.accepted();
+    if !limiter.check_rate(addr.ip()) {
+        debug!("{} exceeds accept rate, close", addr);
+        return;
+    }
+    let client_handle = client_handle.clone();
+    let limiter = limiter.clone();
+    sessions.spawn(async move {
+        let Some(_permit) = limiter.acquire(addr.ip()).await else {
+            debug!("{} exceeds session limits, close", addr);
+            return;
+        };
+        if let Err(e) = client_handle.handle(sock, addr).await {
+            client_handle.shared.metrics.error(&e);
+            warn!("handle {} fail: {}", addr, e);
+        }
+    });
+}
+
+/// state shared by the sessions of all listeners
+#[derive(Default)]
+pub(crate) struct Shared {
+    timeouts: Timeouts,
+    shaper: Shaper,
+    registry: SessionRegistry,
+    metrics: Metrics,
+    access_log: Option<Box<dyn AccessLogSink>>,
+    hooks: Vec<Arc<dyn SessionHook>>,
+}
+
+pub(crate) struct ClientHandle<C> {
+    endpoint: Endpoint<C>,
+    shared: Arc<Shared>,
+}
+
+impl<C> ClientHandle<C> {
     async fn handle<T>(&self, sock: T, addr: SocketAddr) -> Result<(), ProxyError>
     where
-        T: AsyncRead + AsyncWrite + Unpin,
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        let mut stream = BufReader::new(sock);
-        match stream.try_peek_byte().await {
-            Ok(Some(0x05)) => self.socks5_handle.handle(&self.connector, stream).await?,
-            Ok(Some(_)) => self.http_handle.handle(&self.connector, stream).await?,
-            Ok(None) => {
-                debug!("local socket({}) EOF with no data", addr);
+        let _active = self.shared.metrics.active();
+        let deadline = self.shared.timeouts.handshake.map(|t| Instant::now() + t);
+        let client = ClientInfo::new(addr);
+        for hook in &self.shared.hooks {
+            hook.on_accept(&client).await?;
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.endpoint.tls {
+            let accept = async {
+                tls.accept(sock)
+                    .await
+                    .map_err(|e| io_fail!(e, "tls handshake with {}", addr))
+            };
+            let sock = with_deadline(deadline, accept).await?;
+            let client = ClientInfo {
+                user: tls.peer_identity(sock.get_ref().1),
+                ..client
+            };
+            if client.user.is_some() {
+                debug!("{} authenticated by client certificate", client);
             }
+            return self.dispatch(sock, client, deadline).await;
+        }
+        self.dispatch(sock, client, deadline).await
+    }
+
+    async fn dispatch<T>(
+        &self,
+        sock: T,
+        client: ClientInfo,
+        deadline: Option<Instant>,
+    ) -> Result<(), ProxyError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let detected = with_deadline(deadline, self.detect(sock, &client)).await;
+        let (protocol, io) = match detected {
+            Ok(Some(detected)) => detected,
+            Ok(None) => return Ok(()),
             Err(e) => {
-                bail!("read local socket({}) fail: {}", addr, e);
+                self.shared
+                    .metrics
+                    .handshake("unknown", handshake_outcome(Some(&e)));
+                return Err(e);
+            }
+        };
+        let policy = self.endpoint.policy.load();
+        let mut session = Session::new(self, &policy, client, protocol.name(), deadline);
+        let result = tokio::select! {
+            result = protocol.handle(&session, io) => result,
+            _ = session.killed() => Err(ProxyError::Killed),
+        };
+        session.finish_handshake(&result);
+        session.set_result(&result);
+        result
+    }
+
+    /// probe the registered protocols, `None` if the client closes without sending data
+    async fn detect<T>(
+        &self,
+        mut sock: T,
+        client: &ClientInfo,
+    ) -> Result<Option<(&dyn ProtocolHandler<C>, BufReader<BoxedTransport>)>, ProxyError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut prefix = Vec::new();
+        let mut buf = [0; MAX_DETECT_LEN];
+        let protocol = loop {
+            let n = match sock.read(&mut buf[prefix.len()..]).await {
+                Ok(n) => n,
+                Err(e) => bail!("read local socket({}) fail: {}", client.addr, e),
+            };
+            if n == 0 && prefix.is_empty() {
+                debug!("local socket({}) EOF with no data", client.addr);
+                return Ok(None);
             }
+            prefix.extend_from_slice(&buf[prefix.len()..prefix.len() + n]);
+
+            // the first match wins unless a protocol registered before it is undecided,
+            // so the choice does not depend on how the client's bytes are segmented
+            let mut undecided = false;
+            let mut found = None;
+            for protocol in &self.endpoint.protocols {
+                match protocol.detect(&prefix) {
+                    Detect::Match => {
+                        found = Some(protocol);
+                        break;
+                    }
+                    Detect::NeedMore => undecided = true,
+                    Detect::NoMatch => {}
+                }
+            }
+            let last = n == 0 || prefix.len() >= MAX_DETECT_LEN;
+            match found {
+                Some(protocol) if !undecided || last => break protocol,
+                None if n == 0 => {
+                    return Err(protocol_fail!("unexpected EOF from {}", client.addr));
+                }
+                None if !undecided || last => {
+                    return Err(protocol_fail!("unknown protocol from {}", client.addr));
+                }
+                _ => {}
+            }
+        };
+        let io: BoxedTransport = Box::new(Rewind::new(prefix, sock));
+        Ok(Some((protocol.as_ref(), BufReader::new(io))))
+    }
+}
+
+/// accepted client, passed to protocol handles
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    /// peer address
+    pub addr: SocketAddr,
+    /// authenticated identity, e.g. from the client certificate
+    pub user: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr, user: None }
+    }
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.user {
+            Some(user) => write!(f, "{}@{}", user, self.addr),
+            None => write!(f, "{}", self.addr),
         }
-        Ok(())
     }
 }
 
@@ -135,12 +607,31 @@ pub struct TcpIncoming {
     listener: TcpListener,
 }
 
+impl TcpIncoming {
+    pub fn from_listener(listener: TcpListener) -> Self {
+        Self { listener }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.listener.local_addr()
+    }
+}
+
 impl Stream for TcpIncoming {
     type Item = Result<(TcpStream, SocketAddr), Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let (sock, addr) = ready!(Pin::new(&mut self.listener).poll_accept(cx))?;
-        sock.set_nodelay(true)?;
+        if let Err(e) = sock.set_nodelay(true) {
+            debug!("set nodelay on {} fail: {}", addr, e);
+        }
         Poll::Ready(Some(Ok((sock, addr))))
     }
 }
+
+#[cfg(unix)]
+impl std::os::fd::AsFd for TcpIncoming {
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
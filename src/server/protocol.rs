@@ -0,0 +1,225 @@
+use std::io::Error;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadBuf};
+
+use crate::ProxyError;
+use crate::server::Session;
+use crate::transport::BoxedTransport;
+
+/// result of probing the first bytes of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detect {
+    Match,
+    NoMatch,
+    /// undecided, probe again when more bytes arrive
+    NeedMore,
+}
+
+/// protocol served on the mixed port, selected by probing the first bytes
+#[async_trait]
+pub trait ProtocolHandler<C>: Send + Sync {
+    /// unique name, used to replace or disable the protocol
+    fn name(&self) -> &str;
+
+    fn detect(&self, prefix: &[u8]) -> Detect;
+
+    /// serve the connection, the probed bytes are still readable from `io`
+    async fn handle(
+        &self,
+        session: &Session<'_, C>,
+        io: BufReader<BoxedTransport>,
+    ) -> Result<(), ProxyError>;
+}
+
+pin_project! {
+    /// replay bytes read during detection before reading from the inner stream
+    pub(crate) struct Rewind<T> {
+        prefix: Vec<u8>,
+        pos: usize,
+        #[pin]
+        inner: T,
+    }
+}
+
+impl<T> Rewind<T> {
+    pub(crate) fn new(prefix: Vec<u8>, inner: T) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Rewind<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let this = self.project();
+        if *this.pos < this.prefix.len() {
+            let remain = &this.prefix[*this.pos..];
+            let n = remain.len().min(buf.remaining());
+            buf.put_slice(&remain[..n]);
+            *this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        this.inner.poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for Rewind<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{Detect, ProtocolHandler};
+    use crate::ProxyError;
+    use crate::connector::DirectConnector;
+    use crate::server::{HttpHandle, ProxyServer, Session, Socks5Handle};
+    use crate::transport::BoxedTransport;
+
+    struct Echo;
+
+    #[async_trait]
+    impl ProtocolHandler<DirectConnector> for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn detect(&self, prefix: &[u8]) -> Detect {
+            if prefix.starts_with(b"ECHO\n") {
+                Detect::Match
+            } else if b"ECHO\n".starts_with(prefix) {
+                Detect::NeedMore
+            } else {
+                Detect::NoMatch
+            }
+        }
+
+        async fn handle(
+            &self,
+            _: &Session<'_, DirectConnector>,
+            mut io: BufReader<BoxedTransport>,
+        ) -> Result<(), ProxyError> {
+            let mut data = Vec::new();
+            io.read_to_end(&mut data).await?;
+            io.write_all(&data).await?;
+            Ok(())
+        }
+    }
+
+    /// replies with its name to clients starting with its magic
+    struct Magic(&'static str, &'static [u8]);
+
+    #[async_trait]
+    impl ProtocolHandler<DirectConnector> for Magic {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn detect(&self, prefix: &[u8]) -> Detect {
+            if prefix.starts_with(self.1) {
+                Detect::Match
+            } else if self.1.starts_with(prefix) {
+                Detect::NeedMore
+            } else {
+                Detect::NoMatch
+            }
+        }
+
+        async fn handle(
+            &self,
+            _: &Session<'_, DirectConnector>,
+            mut io: BufReader<BoxedTransport>,
+        ) -> Result<(), ProxyError> {
+            io.write_all(self.0.as_bytes()).await?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_detect() {
+        let (http, socks5) = (HttpHandle::new(), Socks5Handle::new());
+        let detect = |p: &dyn ProtocolHandler<DirectConnector>, prefix: &[u8]| p.detect(prefix);
+        assert_eq!(detect(&http, b"GET / HTTP/1.1\r\n"), Detect::Match);
+        assert_eq!(detect(&http, b"CONN"), Detect::NeedMore);
+        assert_eq!(detect(&http, b"\x05\x01\x00"), Detect::NoMatch);
+        assert_eq!(detect(&http, b" GET"), Detect::NoMatch);
+        assert_eq!(detect(&socks5, b"\x05\x01\x00"), Detect::Match);
+        assert_eq!(detect(&socks5, b"\x04\x01"), Detect::NoMatch);
+    }
+
+    #[tokio::test]
+    async fn test_custom_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = ProxyServer::from_listener(DirectConnector, listener)
+            .without_protocol("socks5")
+            .with_protocol(Echo);
+        tokio::spawn(server.run());
+
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        sock.write_all(b"EC").await.unwrap();
+        tokio::task::yield_now().await;
+        sock.write_all(b"HO\nhello").await.unwrap();
+        sock.shutdown().await.unwrap();
+        let mut data = Vec::new();
+        sock.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"ECHO\nhello");
+
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        sock.write_all(b"\x05\x01\x00").await.unwrap();
+        let mut data = Vec::new();
+        sock.read_to_end(&mut data).await.unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_detect_priority() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = ProxyServer::from_listener(DirectConnector, listener)
+            .with_protocol(Magic("long", b"abcd"))
+            .with_protocol(Magic("short", b"ab"));
+        tokio::spawn(server.run());
+
+        for (data, expected) in [(&b"abcd"[..], "long"), (b"abx", "short")] {
+            let mut sock = TcpStream::connect(addr).await.unwrap();
+            sock.set_nodelay(true).unwrap();
+            for byte in data {
+                sock.write_all(&[*byte]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let mut reply = String::new();
+            sock.read_to_string(&mut reply).await.unwrap();
+            assert_eq!(reply, expected);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tokio::sync::Notify;
+
+use crate::address::Address;
+use crate::connector::Connected;
+use crate::server::ClientInfo;
+
+/// snapshot of an active session
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub client: ClientInfo,
+    /// name of the protocol handle
+    pub protocol: String,
+    /// request method, e.g. CONNECT or GET
+    pub method: Option<String>,
+    /// requested target, `None` until the proxy request is parsed
+    pub target: Option<Address>,
+    /// address passed to the connector, differs from `target` when a hook
+    /// rewrote it, `None` until the hooks ran
+    pub upstream: Option<Address>,
+    /// ip of the connected upstream, or of an ip target before connecting
+    pub resolved: Option<IpAddr>,
+    /// outbound that connected the target, `None` until connected
+    pub outbound: Option<String>,
+    pub start: SystemTime,
+    /// bytes from the client to the target
+    pub bytes_up: u64,
+    /// bytes from the target to the client
+    pub bytes_down: u64,
+}
+
+/// live state of a session, shared between the session and the registry
+pub(crate) struct SessionEntry {
+    id: u64,
+    client: ClientInfo,
+    protocol: String,
+    start: SystemTime,
+    request: Mutex<Request>,
+    pub(crate) up: Arc<AtomicU64>,
+    pub(crate) down: Arc<AtomicU64>,
+    kill: Notify,
+}
+
+#[derive(Default)]
+struct Request {
+    /// user authenticated after the session started
+    user: Option<String>,
+    method: Option<String>,
+    target: Option<Address>,
+    upstream: Option<Address>,
+    resolved: Option<IpAddr>,
+    outbound: Option<String>,
+}
+
+impl SessionEntry {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    pub(crate) fn set_user(&self, user: &str) {
+        self.request.lock().unwrap().user = Some(user.to_string());
+    }
+
+    pub(crate) fn set_method(&self, method: &str) {
+        self.request.lock().unwrap().method = Some(method.to_string());
+    }
+
+    pub(crate) fn set_target(&self, target: &Address) {
+        let mut request = self.request.lock().unwrap();
+        request.target = Some(target.clone());
+        request.resolved = ip_of(target);
+    }
+
+    /// target after the hooks, before connecting
+    pub(crate) fn set_upstream(&self, upstream: &Address) {
+        let mut request = self.request.lock().unwrap();
+        request.upstream = Some(upstream.clone());
+        request.resolved = ip_of(upstream);
+    }
+
+    pub(crate) fn set_connected(&self, connected: &Connected) {
+        let mut request = self.request.lock().unwrap();
+        request.outbound = Some(connected.outbound.clone());
+        if let Some(peer) = connected.peer {
+            request.resolved = Some(peer.ip());
+        }
+    }
+
+    /// resolves once the session is killed through the registry
+    pub(crate) async fn killed(&self) {
+        self.kill.notified().await
+    }
+
+    pub(crate) fn info(&self) -> SessionInfo {
+        let request = self.request.lock().unwrap();
+        let mut client = self.client.clone();
+        if request.user.is_some() {
+            client.user = request.user.clone();
+        }
+        SessionInfo {
+            id: self.id,
+            client,
+            protocol: self.protocol.clone(),
+            method: request.method.clone(),
+            target: request.target.clone(),
+            upstream: request.upstream.clone(),
+            resolved: request.resolved,
+            outbound: request.outbound.clone(),
+            start: self.start,
+            bytes_up: self.up.load(Ordering::Relaxed),
+            bytes_down: self.down.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn ip_of(target: &Address) -> Option<IpAddr> {
+    match target {
+        Address::Sock(addr) => Some(addr.ip()),
+        Address::Domain(host, _) => host.parse().ok(),
+    }
+}
+
+/// active sessions of a `ProxyServer`, cheap to clone
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: AtomicU64,
+    sessions: Mutex<BTreeMap<u64, Arc<SessionEntry>>>,
+}
+
+impl SessionRegistry {
+    /// snapshot of all active sessions ordered by id
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let sessions = self.inner.sessions.lock().unwrap();
+        sessions.values().map(|e| e.info()).collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<SessionInfo> {
+        let sessions = self.inner.sessions.lock().unwrap();
+        sessions.get(&id).map(|e| e.info())
+    }
+
+    /// close the session, `false` if it is not active
+    pub fn kill(&self, id: u64) -> bool {
+        let sessions = self.inner.sessions.lock().unwrap();
+        match sessions.get(&id) {
+            Some(entry) => {
+                entry.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// close the sessions matching `f`, returns the number of killed sessions
+    pub fn kill_matching<F>(&self, mut f: F) -> usize
+    where
+        F: FnMut(&SessionInfo) -> bool,
+    {
+        let sessions = self.inner.sessions.lock().unwrap();
+        let mut killed = 0;
+        for entry in sessions.values() {
+            if f(&entry.info()) {
+                entry.kill.notify_one();
+                killed += 1;
+            }
+        }
+        killed
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn register(&self, client: ClientInfo, protocol: &str) -> Registered {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Arc::new(SessionEntry {
+            id,
+            client,
+            protocol: protocol.to_string(),
+            start: SystemTime::now(),
+            request: Mutex::new(Request::default()),
+            up: Arc::new(AtomicU64::new(0)),
+            down: Arc::new(AtomicU64::new(0)),
+            kill: Notify::new(),
+        });
+        self.inner
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(id, entry.clone());
+        Registered {
+            registry: self.clone(),
+            entry,
+        }
+    }
+}
+
+/// registration of a session, removed from the registry on drop
+pub(crate) struct Registered {
+    registry: SessionRegistry,
+    pub(crate) entry: Arc<SessionEntry>,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        let mut sessions = self.registry.inner.sessions.lock().unwrap();
+        sessions.remove(&self.entry.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::connector::DirectConnector;
+    use crate::testing::{bind, open_tunnel, spawn};
+
+    #[tokio::test]
+    async fn test_registry() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut sock, _) = target.accept().await.unwrap();
+            let mut buf = [0; 5];
+            sock.read_exact(&mut buf).await.unwrap();
+            sock.write_all(b"world!").await.unwrap();
+            let mut buf = Vec::new();
+            let _ = sock.read_to_end(&mut buf).await;
+        });
+
+        let server = bind(DirectConnector).await;
+        let registry = server.registry();
+        let proxy = spawn(server);
+
+        let target = format!("127.0.0.1:{target_port}");
+        let mut sock = open_tunnel(proxy, target).await.unwrap();
+        sock.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 6];
+        sock.read_exact(&mut buf).await.unwrap();
+
+        let sessions = registry.sessions();
+        assert_eq!(sessions.len(), 1);
+        let session = &sessions[0];
+        assert_eq!(session.protocol, "http");
+        assert_eq!(session.client.addr, sock.local_addr().unwrap());
+        assert_eq!(
+            session.target.as_ref().unwrap().to_string(),
+            format!("127.0.0.1:{target_port}")
+        );
+        assert_eq!((session.bytes_up, session.bytes_down), (5, 6));
+
+        drop(sock);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(registry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_kill() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut sock, _) = target.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let _ = sock.read_to_end(&mut buf).await;
+                });
+            }
+        });
+
+        let server = bind(DirectConnector).await;
+        let registry = server.registry();
+        let proxy = spawn(server);
+
+        let mut socks = Vec::new();
+        for _ in 0..3 {
+            let target = format!("127.0.0.1:{target_port}");
+            socks.push(open_tunnel(proxy, target).await.unwrap());
+        }
+        let first = registry.sessions()[0].id;
+        assert!(registry.kill(first));
+        let mut buf = Vec::new();
+        socks[0].read_to_end(&mut buf).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!registry.kill(first));
+        assert_eq!(registry.len(), 2);
+
+        let killed =
+            registry.kill_matching(|s| s.target.as_ref().is_some_and(|t| t.port() == target_port));
+        assert_eq!(killed, 2);
+        for sock in &mut socks[1..] {
+            sock.read_to_end(&mut buf).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::ProxyError;
+use crate::connector::Connector;
+use crate::server::{Acl, Credentials};
+
+/// connector, acl and credentials of a listener, replaced as a whole by `ReloadHandle`,
+/// sessions keep the policy they started with, so established tunnels stay on
+/// their outbound
+pub struct Policy<C> {
+    pub(crate) connector: C,
+    /// name of the connector
+    pub(crate) outbound: String,
+    pub(crate) acl: Option<Acl>,
+    pub(crate) credentials: Option<Credentials>,
+}
+
+impl<C> Policy<C>
+where
+    C: Connector,
+{
+    pub fn new(connector: C) -> Self {
+        Self {
+            outbound: connector.name().to_string(),
+            connector,
+            acl: None,
+            credentials: None,
+        }
+    }
+}
+
+impl<C> Policy<C> {
+    /// check each target against `acl` before connecting
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// require a username and password, clients authenticated by a TLS
+    /// certificate are accepted without
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn connector(&self) -> &C {
+        &self.connector
+    }
+}
+
+/// value replaced while readers keep the snapshot they loaded
+pub(crate) struct Swap<T>(RwLock<Arc<T>>);
+
+impl<T> Swap<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Self(RwLock::new(Arc::new(value)))
+    }
+
+    pub(crate) fn load(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// `None` once the value is shared with readers
+    pub(crate) fn get_mut(&mut self) -> Option<&mut T> {
+        Arc::get_mut(self.0.get_mut().unwrap())
+    }
+}
+
+/// policy of a listener shared by its `ClientHandle` and the `ReloadHandle`
+pub(crate) type PolicySlot<C> = Arc<Swap<Policy<C>>>;
+
+/// replace the policies of a running server, see `ProxyServer::reload_handle`
+pub struct ReloadHandle<C> {
+    slots: Arc<OnceLock<Vec<PolicySlot<C>>>>,
+}
+
+impl<C> Clone for ReloadHandle<C> {
+    fn clone(&self) -> Self {
+        Self {
+            slots: self.slots.clone(),
+        }
+    }
+}
+
+impl<C> ReloadHandle<C> {
+    pub(crate) fn new() -> Self {
+        Self {
+            slots: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// called once by `ProxyServer::run`
+    pub(crate) fn attach(&self, slots: Vec<PolicySlot<C>>) {
+        let _ = self.slots.set(slots);
+    }
+
+    /// number of listeners, the primary listener first, 0 until the server runs
+    pub fn len(&self) -> usize {
+        self.slots.get().map_or(0, Vec::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// current policy of a listener, the primary listener is 0
+    pub fn policy(&self, index: usize) -> Option<Arc<Policy<C>>> {
+        self.slots.get()?.get(index).map(|slot| slot.load())
+    }
+
+    /// replace the policy of every listener at once, in the order the listeners
+    /// were added after the primary one, new sessions use the new policies
+    pub fn reload(&self, policies: Vec<Policy<C>>) -> Result<(), ProxyError> {
+        let Some(slots) = self.slots.get() else {
+            bail!("reload fail: server is not running");
+        };
+        if slots.len() != policies.len() {
+            return Err(invalid_data!(
+                "reload fail: {} policies for {} listeners",
+                policies.len(),
+                slots.len()
+            ));
+        }
+        let mut guards: Vec<_> = slots.iter().map(|s| s.0.write().unwrap()).collect();
+        for (guard, policy) in guards.iter_mut().zip(policies) {
+            **guard = Arc::new(policy);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::Policy;
+    use crate::connector::DirectConnector;
+    use crate::server::{Acl, Action};
+    use crate::testing::{bind, echo_target, open_tunnel, request, spawn};
+
+    #[tokio::test]
+    async fn test_reload() {
+        let target_addr = echo_target().await;
+        let server = bind(DirectConnector).await;
+        let handle = server.reload_handle();
+        assert!(handle.reload(vec![Policy::new(DirectConnector)]).is_err());
+        let proxy = spawn(server);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(handle.len(), 1);
+
+        let mut established = open_tunnel(proxy, target_addr).await.unwrap();
+
+        let deny = Policy::new(DirectConnector).with_acl(Acl::new(Action::Deny));
+        assert!(handle.reload(vec![]).is_err());
+        handle.reload(vec![deny]).unwrap();
+        let data = request(proxy, target_addr).await;
+        assert!(data.starts_with(b"HTTP/1.1 403"));
+
+        // the tunnel opened before the reload keeps relaying
+        established.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        established.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
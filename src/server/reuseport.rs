@@ -0,0 +1,255 @@
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket, ToSocketAddrs, lookup_host};
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::{Instant, sleep_until};
+use tokio_stream::{Stream, StreamExt};
+
+use super::accept::Backoff;
+use super::limit::Limiter;
+use super::shutdown::ShutdownSignal;
+use super::{
+    AcceptPolicy, ClientHandle, ProxyServer, Running, ShutdownHandle, ShutdownSummary, TcpIncoming,
+    drain, spawn_session,
+};
+use crate::{ProxyError, connector::Connector};
+
+/// backlog of each listener
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// `SO_REUSEPORT` listeners on one address, see `ProxyServer::bind_reuseport`
+///
+/// the server accepts on each listener in its own task, so the kernel spreads
+/// connections and accept work over the runtime workers, the sessions share
+/// the protocols, connector and limits of the server
+pub struct ReusePortIncoming {
+    listeners: Vec<TcpListener>,
+    local_addr: SocketAddr,
+}
+
+impl ReusePortIncoming {
+    /// bind `acceptors` listeners to `addr`, port 0 picks one port shared by all
+    pub async fn bind<A>(addr: A, acceptors: usize) -> Result<Self, ProxyError>
+    where
+        A: ToSocketAddrs,
+    {
+        let mut addr = match lookup_host(addr).await {
+            Ok(mut addrs) => match addrs.next() {
+                Some(addr) => addr,
+                None => bail!("bind reuseport fail: no address"),
+            },
+            Err(e) => return Err(io_fail!(e, "resolve reuseport address")),
+        };
+        let mut listeners = Vec::new();
+        for _ in 0..acceptors.max(1) {
+            let listener = bind_reuseport(addr).map_err(|e| io_fail!(e, "bind {}", addr))?;
+            addr = listener
+                .local_addr()
+                .map_err(|e| io_fail!(e, "bind {}", addr))?;
+            listeners.push(listener);
+        }
+        Ok(Self {
+            listeners,
+            local_addr: addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn acceptors(&self) -> usize {
+        self.listeners.len()
+    }
+}
+
+fn bind_reuseport(addr: SocketAddr) -> Result<TcpListener, Error> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+impl<C> ProxyServer<C, ReusePortIncoming>
+where
+    C: Connector + Send + Sync + 'static,
+    <C as Connector>::Transport: Unpin + Send,
+{
+    /// like `ProxyServer::run`, but each `SO_REUSEPORT` listener and each added
+    /// listener accepts and spawns its sessions in its own task
+    pub async fn run(self) -> Result<ShutdownSummary, ProxyError> {
+        let mut signal = self.shutdown.signal();
+        let Running {
+            incoming,
+            client_handle,
+            listeners,
+            limiter,
+            accept_policy,
+        } = self.start();
+        let stop = ShutdownHandle::new();
+        let mut acceptors = JoinSet::new();
+        for listener in incoming.listeners {
+            acceptors.spawn(accept(
+                TcpIncoming::from_listener(listener),
+                client_handle.clone(),
+                limiter.clone(),
+                accept_policy.clone(),
+                stop.signal(),
+            ));
+        }
+        for (listener, handle) in listeners {
+            acceptors.spawn(accept(
+                listener,
+                handle,
+                limiter.clone(),
+                accept_policy.clone(),
+                stop.signal(),
+            ));
+        }
+
+        let grace = loop {
+            tokio::select! {
+                grace = signal.recv() => break grace,
+                Some(result) = acceptors.join_next() => {
+                    if let Err(e) = joined(result) {
+                        stop.shutdown(Duration::ZERO);
+                        while let Some(result) = acceptors.join_next().await {
+                            if let Ok(mut sessions) = joined(result) {
+                                sessions.detach_all();
+                            }
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        };
+
+        stop.shutdown(grace);
+        let mut sessions = Vec::new();
+        while let Some(result) = acceptors.join_next().await {
+            sessions.extend(joined(result).ok());
+        }
+        Ok(drain(sessions, grace).await)
+    }
+}
+
+/// accept from `incoming` and spawn the sessions until `stop`,
+/// the sessions still running are returned to be drained
+async fn accept<C, I, T>(
+    mut incoming: I,
+    client_handle: Arc<ClientHandle<C>>,
+    limiter: Arc<Limiter>,
+    accept_policy: AcceptPolicy,
+    mut stop: ShutdownSignal,
+) -> Result<JoinSet<()>, ProxyError>
+where
+    C: Send + Sync + 'static,
+    I: Stream<Item = Result<(T, SocketAddr), Error>> + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut sessions = JoinSet::new();
+    let mut backoff = Backoff::new();
+    let mut retry_at = None;
+    let mut incoming_done = false;
+    loop {
+        let result = tokio::select! {
+            _ = stop.recv() => return Ok(sessions),
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => continue,
+            _ = sleep_until(retry_at.unwrap_or_else(Instant::now)), if retry_at.is_some() => {
+                retry_at = None;
+                continue;
+            }
+            result = incoming.next(), if retry_at.is_none() && !incoming_done => match result {
+                Some(Ok((sock, addr))) => {
+                    spawn_session(&mut sessions, &client_handle, &limiter, sock, addr);
+                    Ok(())
+                }
+                Some(Err(e)) => Err(e),
+                None => {
+                    incoming_done = true;
+                    Ok(())
+                }
+            },
+        };
+        match result {
+            Ok(()) => backoff.reset(),
+            Err(e) => match backoff.on_error(&accept_policy, &e) {
+                Ok(at) => retry_at = at,
+                Err(e) => {
+                    sessions.detach_all();
+                    return Err(e);
+                }
+            },
+        }
+    }
+}
+
+/// result of an acceptor task, they are never aborted, so only panics are resumed
+fn joined<T>(result: Result<T, JoinError>) -> T {
+    result.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::ReusePortIncoming;
+    use crate::connector::DirectConnector;
+    use crate::server::{ConnectionLimits, Metrics, ProxyServer};
+    use crate::testing::{echo_target, open_tunnel, pong_target, request};
+
+    #[tokio::test]
+    async fn test_reuseport() {
+        let target_addr = pong_target().await;
+        let incoming = ReusePortIncoming::bind("127.0.0.1:0", 4).await.unwrap();
+        assert_eq!(incoming.acceptors(), 4);
+        let proxy = incoming.local_addr();
+        let metrics = Metrics::new();
+        let server =
+            ProxyServer::from_incoming(DirectConnector, incoming).with_metrics(metrics.clone());
+        tokio::spawn(server.run());
+
+        for _ in 0..8 {
+            let data = request(proxy, target_addr).await;
+            assert_eq!(data, b"HTTP/1.1 200 Ok\r\n\r\npong");
+        }
+        assert!(
+            metrics
+                .render()
+                .contains("proxy_accepted_connections_total 8\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reuseport_shared_limits() {
+        let target_addr = echo_target().await;
+        let incoming = ReusePortIncoming::bind("127.0.0.1:0", 4).await.unwrap();
+        let proxy = incoming.local_addr();
+        let server =
+            ProxyServer::from_incoming(DirectConnector, incoming).with_limits(ConnectionLimits {
+                max_sessions: Some(1),
+                ..Default::default()
+            });
+        let handle = server.shutdown_handle();
+        let server = tokio::spawn(server.run());
+
+        // whichever acceptor takes them, the other tunnels count against the same limit
+        let _held = open_tunnel(proxy, target_addr).await.unwrap();
+        for _ in 0..8 {
+            assert!(open_tunnel(proxy, target_addr).await.is_none());
+        }
+
+        handle.shutdown(Duration::from_millis(50));
+        let summary = server.await.unwrap().unwrap();
+        assert_eq!(summary.drained, 0);
+        assert_eq!(summary.aborted, 1);
+    }
+}
@@ -0,0 +1,180 @@
+use std::net::{IpAddr, SocketAddr};
+
+use async_trait::async_trait;
+
+use crate::ProxyError;
+use crate::address::Address;
+use crate::server::{DomainPattern, SessionHook, SessionInfo};
+
+/// map targets to other addresses before connecting, e.g. production hostnames
+/// to a staging environment, the first matching rule applies
+///
+/// added with `ProxyServer::with_hook`, the acl and the connector see the
+/// rewritten target, the registry and the access log record it as `upstream`
+#[derive(Debug, Clone, Default)]
+pub struct Rewrite {
+    rules: Vec<RewriteRule>,
+}
+
+impl Rewrite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rule(mut self, rule: RewriteRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// target of the first matching rule, `None` if no rule matches
+    pub fn rewrite(&self, target: &Address) -> Option<Address> {
+        let rule = self.rules.iter().find(|r| r.matches(target))?;
+        Some(rule.apply(target))
+    }
+}
+
+#[async_trait]
+impl SessionHook for Rewrite {
+    async fn before_connect(
+        &self,
+        _session: &SessionInfo,
+        target: Address,
+    ) -> Result<Address, ProxyError> {
+        Ok(self.rewrite(&target).unwrap_or(target))
+    }
+}
+
+/// `from` is `host[:port]` with a `DomainPattern` or an ip as host, `to` is
+/// `host[:port]` or `:port`, the host or port left out is kept,
+/// `*.prod.example.com` to `*.staging.example.com` keeps the subdomain
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    from: DomainPattern,
+    from_port: Option<u16>,
+    to: Option<String>,
+    to_port: Option<u16>,
+}
+
+impl RewriteRule {
+    pub fn new(from: &str, to: &str) -> Result<Self, ProxyError> {
+        let (from_host, from_port) = split_port(from)?;
+        let (to_host, to_port) = split_port(to)?;
+        let pattern = match from_host {
+            "" => DomainPattern::new("*"),
+            host => DomainPattern::new(host),
+        };
+        let to_host = match to_host {
+            "" | "*" => None,
+            host => Some(host.trim_end_matches('.').to_ascii_lowercase()),
+        };
+        if let Some(host) = &to_host
+            && host.contains('*')
+            && (!host.starts_with("*.")
+                || host[1..].contains('*')
+                || pattern.wildcard_suffix().is_none())
+        {
+            return Err(invalid_data!(
+                "invalid rewrite to {}: only *.domain from a wildcard source",
+                to
+            ));
+        }
+        if to_host.is_none() && to_port.is_none() {
+            return Err(invalid_data!("invalid rewrite to {}: no host or port", to));
+        }
+        Ok(Self {
+            from: pattern,
+            from_port,
+            to: to_host,
+            to_port,
+        })
+    }
+
+    fn matches(&self, target: &Address) -> bool {
+        self.from_port.is_none_or(|port| port == target.port()) && self.from.matches(&target.host())
+    }
+
+    fn apply(&self, target: &Address) -> Address {
+        let port = self.to_port.unwrap_or(target.port());
+        let host = match (&self.to, self.from.wildcard_suffix()) {
+            (None, _) => target.host(),
+            (Some(to), Some(suffix)) if to.starts_with('*') => {
+                let domain = target.host().trim_end_matches('.').to_ascii_lowercase();
+                let prefix = &domain[..domain.len() - suffix.len()];
+                format!("{}{}", prefix, &to[1..])
+            }
+            (Some(to), _) => to.clone(),
+        };
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Address::Sock(SocketAddr::new(ip, port)),
+            Err(_) => Address::Domain(host, port),
+        }
+    }
+}
+
+/// split `host[:port]`, an ipv6 host with a port is in brackets
+fn split_port(s: &str) -> Result<(&str, Option<u16>), ProxyError> {
+    let (host, port) = match s.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => (host, Some(port)),
+        _ => (s, None),
+    };
+    let port = match port {
+        Some(port) => Some(
+            port.parse()
+                .map_err(|_| invalid_data!("invalid port in {}", s))?,
+        ),
+        None => None,
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    Ok((host, port))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rewrite, RewriteRule};
+    use crate::address::Address;
+
+    fn rewrite(rewrite: &Rewrite, target: &str) -> Option<String> {
+        let target: Address = target.parse().unwrap();
+        rewrite.rewrite(&target).map(|a| a.to_string())
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let rules = [
+            ("api.internal:443", "10.0.0.5:8443"),
+            ("*.prod.example.com", "*.staging.example.com"),
+            ("legacy.example.com", "new.example.com"),
+            (":80", ":8080"),
+            ("[::1]:22", "127.0.0.1"),
+        ];
+        let mut r = Rewrite::new();
+        for (from, to) in rules {
+            r = r.with_rule(RewriteRule::new(from, to).unwrap());
+        }
+
+        let addr = rewrite(&r, "api.internal:443");
+        assert_eq!(addr.as_deref(), Some("10.0.0.5:8443"));
+        assert_eq!(rewrite(&r, "api.internal:8443"), None);
+        let addr = rewrite(&r, "Auth.PROD.example.com:443");
+        assert_eq!(addr.as_deref(), Some("auth.staging.example.com:443"));
+        assert_eq!(rewrite(&r, "prod.example.com:443"), None);
+        let addr = rewrite(&r, "legacy.example.com:443");
+        assert_eq!(addr.as_deref(), Some("new.example.com:443"));
+        let addr = rewrite(&r, "legacy.example.com:80");
+        assert_eq!(addr.as_deref(), Some("new.example.com:80"));
+        assert_eq!(rewrite(&r, "10.1.2.3:80").as_deref(), Some("10.1.2.3:8080"));
+        assert_eq!(rewrite(&r, "[::1]:22").as_deref(), Some("127.0.0.1:22"));
+
+        assert!(RewriteRule::new("a.example.com", "*.example.org").is_err());
+        assert!(RewriteRule::new("*.example.com", "a.*.example.org").is_err());
+        assert!(RewriteRule::new("a.example.com:https", "b.example.com").is_err());
+        assert!(RewriteRule::new("a.example.com", "*").is_err());
+    }
+}
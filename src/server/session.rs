@@ -0,0 +1,387 @@
+use std::future::Future;
+use std::io::ErrorKind;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Instant, timeout, timeout_at};
+
+use crate::ProxyError;
+use crate::address::Address;
+use crate::connector::Connector;
+use crate::server::access_log::{AccessRecord, unix_millis};
+use crate::server::acl::Action;
+use crate::server::registry::Registered;
+use crate::server::reload::Policy;
+use crate::server::{ClientHandle, ClientInfo, SessionInfo};
+use crate::util::DuplexCopy;
+
+/// timeouts applied to each accepted connection, `None` waits forever
+#[derive(Debug, Clone, Default)]
+pub struct Timeouts {
+    /// from accept until the proxy request is read, including TLS handshake
+    pub handshake: Option<Duration>,
+    /// connecting to the target through the connector
+    pub connect: Option<Duration>,
+    /// close the tunnel when no data is relayed in both directions
+    pub idle: Option<Duration>,
+}
+
+/// context of an accepted connection, passed to protocol handles
+pub struct Session<'a, C> {
+    handle: &'a ClientHandle<C>,
+    /// policy of the listener when the session started
+    policy: &'a Policy<C>,
+    client: ClientInfo,
+    /// client with the user authenticated by the protocol handle
+    authenticated: OnceLock<ClientInfo>,
+    handshake_deadline: Option<Instant>,
+    handshake_done: AtomicBool,
+    registered: Registered,
+    started: Instant,
+    /// close reason and error message, `None` if the session is aborted
+    close: Option<(&'static str, Option<String>)>,
+}
+
+impl<'a, C> Session<'a, C> {
+    pub(crate) fn new(
+        handle: &'a ClientHandle<C>,
+        policy: &'a Policy<C>,
+        client: ClientInfo,
+        protocol: &str,
+        handshake_deadline: Option<Instant>,
+    ) -> Self {
+        let registered = handle.shared.registry.register(client.clone(), protocol);
+        Self {
+            handle,
+            policy,
+            client,
+            authenticated: OnceLock::new(),
+            handshake_deadline,
+            handshake_done: AtomicBool::new(false),
+            registered,
+            started: Instant::now(),
+            close: None,
+        }
+    }
+
+    /// id in the session registry
+    pub fn id(&self) -> u64 {
+        self.registered.entry.id()
+    }
+
+    /// snapshot of the session as shown in the registry
+    pub fn info(&self) -> SessionInfo {
+        self.registered.entry.info()
+    }
+
+    pub fn client(&self) -> &ClientInfo {
+        self.authenticated.get().unwrap_or(&self.client)
+    }
+
+    /// credentials are configured and the client is not authenticated by its certificate
+    pub fn auth_required(&self) -> bool {
+        self.policy.credentials.is_some() && self.client().user.is_none()
+    }
+
+    /// check `user` and `password` against the configured credentials,
+    /// the user is recorded on success
+    pub fn authenticate(&self, user: &str, password: &str) -> Result<(), ProxyError> {
+        let Some(credentials) = &self.policy.credentials else {
+            return Ok(());
+        };
+        if !credentials.verify(user, password) {
+            debug!("{} authentication as {:?} fail", self.client, user);
+            return Err(ProxyError::Unauthorized);
+        }
+        let client = ClientInfo {
+            user: Some(user.to_string()),
+            ..self.client.clone()
+        };
+        if self.authenticated.set(client).is_ok() {
+            self.registered.entry.set_user(user);
+        }
+        Ok(())
+    }
+
+    pub fn connector(&self) -> &C {
+        &self.policy.connector
+    }
+
+    /// resolves once the session is killed, see `SessionRegistry::kill`
+    pub(crate) async fn killed(&self) {
+        self.registered.entry.killed().await
+    }
+
+    /// record the request method, reported in the registry and the access log
+    pub fn set_method(&self, method: &str) {
+        self.registered.entry.set_method(method);
+    }
+
+    /// record the result of the protocol handle for the access log
+    pub(crate) fn set_result<T>(&mut self, result: &Result<T, ProxyError>) {
+        self.close = Some(match result {
+            Ok(_) => ("ok", None),
+            Err(e) => (e.kind_name(), Some(e.to_string())),
+        });
+    }
+
+    /// run the protocol handshake before the handshake deadline
+    pub async fn handshake<F, T>(&self, fut: F) -> Result<T, ProxyError>
+    where
+        F: Future<Output = Result<T, ProxyError>>,
+    {
+        with_deadline(self.handshake_deadline, fut).await
+    }
+
+    /// record the handshake outcome once, on connect or when the handle returns
+    pub(crate) fn finish_handshake<T>(&self, result: &Result<T, ProxyError>) {
+        if !self.handshake_done.swap(true, Ordering::Relaxed) {
+            let outcome = handshake_outcome(result.as_ref().err());
+            let protocol = self.registered.entry.protocol();
+            self.handle.shared.metrics.handshake(protocol, outcome);
+        }
+    }
+
+    /// close relayed tunnels without data in both directions for this long
+    pub(crate) fn idle_timeout(&self) -> Option<Duration> {
+        self.handle.shared.timeouts.idle
+    }
+
+    /// relay until both directions are closed, fails if the tunnel stays idle too long,
+    /// the left side of `copy` must be the client for bandwidth shaping
+    pub async fn relay<L, R>(&self, copy: DuplexCopy<L, R>) -> Result<(usize, usize), ProxyError>
+    where
+        L: AsyncRead + AsyncWrite + Unpin,
+        R: AsyncRead + AsyncWrite + Unpin,
+    {
+        let entry = &self.registered.entry;
+        let copy = copy.with_counters(entry.up.clone(), entry.down.clone());
+        let (up, down) = self.handle.shared.metrics.byte_counters();
+        let copy = copy.with_counters(up, down);
+        let copy = self.handle.shared.shaper.apply(self.client(), copy);
+        let copy = match self.idle_timeout() {
+            Some(idle) => copy.with_idle_timeout(idle),
+            None => copy,
+        };
+        let (up, down) = copy.await.map_err(|e| match e.kind() {
+            ErrorKind::TimedOut => ProxyError::IdleTimeout,
+            _ => e.into(),
+        })?;
+        debug!(
+            "session {} of {} closed, up {} down {}",
+            self.id(),
+            self.client(),
+            up,
+            down
+        );
+        Ok((up, down))
+    }
+}
+
+impl<C> Session<'_, C>
+where
+    C: Connector + Sync,
+{
+    /// run the hooks, check the acl and connect to the target within the connect timeout,
+    /// hooks may connect to another address than `addr`
+    pub async fn connect(&self, addr: &Address) -> Result<C::Transport, ProxyError> {
+        self.registered.entry.set_target(addr);
+        self.finish_handshake(&Ok::<_, ProxyError>(()));
+        let hooks = &self.handle.shared.hooks;
+        let info = self.info();
+        for hook in hooks {
+            hook.on_handshake(&info).await?;
+        }
+        let mut target = addr.clone();
+        for hook in hooks {
+            target = hook.before_connect(&info, target).await?;
+        }
+        if target.to_string() != addr.to_string() {
+            debug!("{} to {} rewritten to {}", self.client(), addr, target);
+        }
+        let addr = &target;
+        self.registered.entry.set_upstream(addr);
+        if let Some(acl) = &self.policy.acl
+            && acl.check(self.client(), addr) == Action::Deny
+        {
+            debug!("{} to {} denied by acl", self.client(), addr);
+            return Err(ProxyError::Denied(addr.clone()));
+        }
+        let connector = &self.policy.connector;
+        let start = Instant::now();
+        let connect = connector.connect_tcp_with_info(addr);
+        let result = match self.handle.shared.timeouts.connect {
+            Some(t) => timeout(t, connect)
+                .await
+                .map_err(|_| ProxyError::ConnectTimeout(addr.clone()))?,
+            None => connect.await,
+        };
+        let (remote, connected) = result.map_err(|e| match e.kind() {
+            // refused by the connector, e.g. `SsrfGuard`
+            ErrorKind::PermissionDenied => {
+                debug!("{} to {} denied: {}", self.client(), addr, e);
+                ProxyError::Denied(addr.clone())
+            }
+            _ => connect_remote_fail!(addr.clone(), "{}", e),
+        })?;
+        self.handle
+            .shared
+            .metrics
+            .connected(&connected.outbound, start.elapsed());
+        self.registered.entry.set_connected(&connected);
+        for hook in hooks {
+            hook.on_connected(&info, addr).await;
+        }
+        Ok(remote)
+    }
+}
+
+impl<C> Drop for Session<'_, C> {
+    fn drop(&mut self) {
+        let shared = &self.handle.shared;
+        if shared.access_log.is_none() && shared.hooks.is_empty() {
+            return;
+        }
+        let info = self.registered.entry.info();
+        let (close, error) = self.close.take().unwrap_or(("aborted", None));
+        let record = AccessRecord {
+            timestamp: unix_millis(std::time::SystemTime::now()),
+            session_id: info.id,
+            client: info.client.addr,
+            user: info.client.user,
+            protocol: info.protocol,
+            method: info.method,
+            target: info.target,
+            upstream: info.upstream,
+            resolved: info.resolved,
+            outbound: info
+                .outbound
+                .unwrap_or_else(|| self.policy.outbound.clone()),
+            bytes_up: info.bytes_up,
+            bytes_down: info.bytes_down,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            close: close.to_string(),
+            error,
+        };
+        if let Some(sink) = &shared.access_log {
+            sink.log(&record);
+        }
+        // `on_close` is async, run it off the dropping task, e.g. a session aborted on shutdown
+        if !shared.hooks.is_empty()
+            && let Ok(runtime) = tokio::runtime::Handle::try_current()
+        {
+            let hooks = shared.hooks.clone();
+            runtime.spawn(async move {
+                for hook in hooks {
+                    hook.on_close(&record).await;
+                }
+            });
+        }
+    }
+}
+
+pub(crate) fn handshake_outcome(error: Option<&ProxyError>) -> &'static str {
+    match error {
+        None => "ok",
+        Some(ProxyError::HandshakeTimeout) => "timeout",
+        Some(_) => "error",
+    }
+}
+
+pub(crate) async fn with_deadline<F, T>(deadline: Option<Instant>, fut: F) -> Result<T, ProxyError>
+where
+    F: Future<Output = Result<T, ProxyError>>,
+{
+    match deadline {
+        Some(deadline) => timeout_at(deadline, fut)
+            .await
+            .map_err(|_| ProxyError::HandshakeTimeout)?,
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Error;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::Timeouts;
+    use crate::address::Address;
+    use crate::connector::{Connector, DirectConnector};
+    use crate::testing::{bind, request, spawn};
+
+    struct Unreachable;
+
+    #[async_trait]
+    impl Connector for Unreachable {
+        type Transport = TcpStream;
+
+        async fn connect_tcp(&self, _: &Address) -> Result<Self::Transport, Error> {
+            std::future::pending().await
+        }
+    }
+
+    async fn serve<C>(connector: C, timeouts: Timeouts) -> std::net::SocketAddr
+    where
+        C: Connector + Send + Sync + 'static,
+        C::Transport: Unpin + Send,
+    {
+        spawn(bind(connector).await.with_timeouts(timeouts))
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let timeouts = Timeouts {
+            handshake: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let addr = serve(DirectConnector, timeouts).await;
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        sock.write_all(b"GET http://example.com/ HT").await.unwrap();
+        let mut response = String::new();
+        sock.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 "));
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let timeouts = Timeouts {
+            connect: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let addr = serve(Unreachable, timeouts).await;
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        sock.write_all(b"\x05\x01\x00\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50")
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        sock.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(&reply[..4], b"\x05\x00\x05\x04");
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut sock, _) = target.accept().await.unwrap();
+            sock.write_all(b"hello").await.unwrap();
+            let mut buf = Vec::new();
+            let _ = sock.read_to_end(&mut buf).await;
+        });
+
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let addr = serve(DirectConnector, timeouts).await;
+        let data = request(addr, format!("127.0.0.1:{target_port}")).await;
+        assert_eq!(data, b"HTTP/1.1 200 Ok\r\n\r\nhello");
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// stop a running `ProxyServer`, in-flight sessions are drained until the grace period ends
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<Option<Duration>>>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> Self {
+        Self {
+            tx: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    /// stop accepting, sessions still active after `grace` are force closed
+    pub fn shutdown(&self, grace: Duration) {
+        self.tx.send_replace(Some(grace));
+    }
+
+    pub(crate) fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.tx.subscribe())
+    }
+}
+
+pub(crate) struct ShutdownSignal(watch::Receiver<Option<Duration>>);
+
+impl ShutdownSignal {
+    /// wait for shutdown, returns the grace period
+    pub(crate) async fn recv(&mut self) -> Duration {
+        let grace = match self.0.wait_for(Option::is_some).await {
+            Ok(grace) => *grace,
+            Err(_) => None,
+        };
+        match grace {
+            Some(grace) => grace,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// result of draining sessions on shutdown
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownSummary {
+    /// sessions finished within the grace period
+    pub drained: usize,
+    /// sessions force closed after the grace period
+    pub aborted: usize,
+    pub elapsed: Duration,
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::connector::DirectConnector;
+    use crate::testing::{bind, open_tunnel};
+
+    #[tokio::test]
+    async fn test_shutdown_drain() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            // the first tunnel is kept open, the second closes shortly after shutdown
+            let (held, _) = target.accept().await.unwrap();
+            let (mut closing, _) = target.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            closing.shutdown().await.unwrap();
+            drop(closing);
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(held);
+        });
+
+        let server = bind(DirectConnector).await;
+        let proxy = server.incoming().local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let server = tokio::spawn(server.run());
+
+        let _held = open_tunnel(proxy, target_addr).await.unwrap();
+        let mut closing = open_tunnel(proxy, target_addr).await.unwrap();
+        handle.shutdown(Duration::from_millis(300));
+
+        let mut buf = Vec::new();
+        closing.read_to_end(&mut buf).await.unwrap();
+        closing.shutdown().await.unwrap();
+        let summary = server.await.unwrap().unwrap();
+        assert_eq!(summary.drained, 1);
+        assert_eq!(summary.aborted, 1);
+        assert!(TcpStream::connect(proxy).await.is_err());
+    }
+}
@@ -1,14 +1,24 @@
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
+use async_trait::async_trait;
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 
 use crate::address::Address;
 use crate::connector::Connector;
 use crate::error::ProxyError;
+use crate::server::{Detect, ProtocolHandler, Session};
+use crate::transport::BoxedTransport;
 use crate::util::{BufIoExt, DuplexCopy};
 
 const SOCKVER: u8 = 0x05;
 
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+/// version of the username/password subnegotiation, RFC 1929
+const PASSWORD_AUTH_VER: u8 = 0x01;
+
 pub struct Socks5Handle {
     //
 }
@@ -24,21 +34,38 @@ impl Socks5Handle {
         Socks5Handle {}
     }
 
-    pub async fn handle<T, C>(&self, connector: &C, mut io: BufReader<T>) -> Result<(), ProxyError>
+    pub async fn handle<T, C>(
+        &self,
+        session: &Session<'_, C>,
+        mut io: BufReader<T>,
+    ) -> Result<(), ProxyError>
     where
         T: AsyncRead + AsyncWrite + Unpin,
-        C: Connector,
+        C: Connector + Sync,
         <C as Connector>::Transport: Unpin,
     {
-        Auth::auth(&mut io).await?;
-        let request = ProxyRequest::parse(&mut io).await?;
-        let mut remote = match connector.connect_tcp(&request.addr).await {
+        let request = session
+            .handshake(async {
+                Auth::auth(session, &mut io).await?;
+                ProxyRequest::parse(&mut io).await
+            })
+            .await?;
+        session.set_method("CONNECT");
+        debug!("{} CONNECT {}", session.client(), request.addr);
+        let mut remote = match session.connect(&request.addr).await {
             Ok(x) => x,
             Err(e) => {
+                // not allowed by ruleset on denial, host unreachable on timeout,
+                // general failure otherwise
+                let rep = match e {
+                    ProxyError::Denied(_) => 0x02,
+                    ProxyError::ConnectTimeout(_) => 0x04,
+                    _ => 0x01,
+                };
                 let _ = io
-                    .write_all(b"\x05\x01\x00\x01\x00\x00\x00\x00\x00\x00")
+                    .write_all(&[0x05, rep, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
                     .await;
-                return Err(connect_remote_fail!(request.addr, "{}", e));
+                return Err(e);
             }
         };
         io.write_all(b"\x05\x00\x00\x01\x00\x00\x00\x00\x00\x00")
@@ -47,23 +74,51 @@ impl Socks5Handle {
         if !buffer.is_empty() {
             remote.write_all(buffer).await?;
         }
-        let _ = DuplexCopy::with_pending(
-            format!("local(to {})", request.addr),
-            io.into_inner(),
-            false,
-            format!("remote({})", request.addr),
-            remote,
-            true,
-        )
-        .await?;
+        session
+            .relay(DuplexCopy::with_pending(
+                format!("local(to {})", request.addr),
+                io.into_inner(),
+                false,
+                format!("remote({})", request.addr),
+                remote,
+                true,
+            ))
+            .await?;
         Ok(())
     }
 }
 
+#[async_trait]
+impl<C> ProtocolHandler<C> for Socks5Handle
+where
+    C: Connector + Send + Sync,
+    <C as Connector>::Transport: Unpin + Send,
+{
+    fn name(&self) -> &str {
+        "socks5"
+    }
+
+    fn detect(&self, prefix: &[u8]) -> Detect {
+        match prefix.first() {
+            Some(&SOCKVER) => Detect::Match,
+            Some(_) => Detect::NoMatch,
+            None => Detect::NeedMore,
+        }
+    }
+
+    async fn handle(
+        &self,
+        session: &Session<'_, C>,
+        io: BufReader<BoxedTransport>,
+    ) -> Result<(), ProxyError> {
+        Socks5Handle::handle(self, session, io).await
+    }
+}
+
 struct Auth;
 
 impl Auth {
-    async fn auth<T>(io: &mut T) -> Result<(), ProxyError>
+    async fn auth<T, C>(session: &Session<'_, C>, io: &mut T) -> Result<(), ProxyError>
     where
         T: AsyncRead + AsyncWrite + Unpin,
     {
@@ -74,11 +129,40 @@ impl Auth {
         }
         let mut methods = vec![0; data[1] as usize];
         io.read_exact(&mut methods).await?;
-        io.write_all(b"\x05\x00").await?;
-        Ok(())
+        if !session.auth_required() {
+            io.write_all(&[SOCKVER, METHOD_NO_AUTH]).await?;
+            return Ok(());
+        }
+        if !methods.contains(&METHOD_PASSWORD) {
+            io.write_all(&[SOCKVER, METHOD_NONE_ACCEPTABLE]).await?;
+            return Err(ProxyError::Unauthorized);
+        }
+        io.write_all(&[SOCKVER, METHOD_PASSWORD]).await?;
+
+        let ver = io.read_u8().await?;
+        if ver != PASSWORD_AUTH_VER {
+            return Err(protocol_fail!("invalid auth version: {}", ver));
+        }
+        let user = read_string(io).await?;
+        let password = read_string(io).await?;
+        let result = session.authenticate(&user, &password);
+        let status = if result.is_ok() { 0x00 } else { 0x01 };
+        io.write_all(&[PASSWORD_AUTH_VER, status]).await?;
+        result
     }
 }
 
+/// read a string prefixed by its one byte length
+async fn read_string<T>(io: &mut T) -> Result<String, ProxyError>
+where
+    T: AsyncRead + Unpin,
+{
+    let len = io.read_u8().await?;
+    let mut data = vec![0; len as usize];
+    io.read_exact(&mut data).await?;
+    String::from_utf8(data).map_err(|_| invalid_data!("invalid auth string"))
+}
+
 struct ProxyRequest {
     addr: Address,
 }
@@ -0,0 +1,366 @@
+use std::fmt;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::CertificateDer;
+use rustls::server::{ClientHello, ResolvesServerCert, ServerConnection, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::ProxyError;
+use crate::util::tls::{load_certs, load_private_key, provider};
+
+/// TLS acceptor, terminates TLS on accepted connections before protocol sniffing
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    inner: tokio_rustls::TlsAcceptor,
+    certs: Option<Arc<CertResolver>>,
+    identity: Option<CertIdentity>,
+}
+
+/// which part of a client certificate is used as the authenticated identity
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CertIdentity {
+    /// subject common name
+    #[default]
+    CommonName,
+    /// first DNS, email or URI subject alternative name
+    SubjectAltName,
+}
+
+impl CertIdentity {
+    fn extract(&self, cert: &CertificateDer<'_>) -> Option<String> {
+        let (_, cert) = X509Certificate::from_der(cert).ok()?;
+        match self {
+            Self::CommonName => cert
+                .subject()
+                .iter_common_name()
+                .find_map(|cn| cn.as_str().ok())
+                .map(ToString::to_string),
+            Self::SubjectAltName => cert
+                .subject_alternative_name()
+                .ok()??
+                .value
+                .general_names
+                .iter()
+                .find_map(|name| match name {
+                    GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => {
+                        Some(s.to_string())
+                    }
+                    _ => None,
+                }),
+        }
+    }
+}
+
+impl TlsAcceptor {
+    /// load certificate chain and private key from PEM files, both can be reloaded later
+    pub fn from_pem_files<P, K>(cert_path: P, key_path: K) -> Result<Self, ProxyError>
+    where
+        P: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let certs = Arc::new(CertResolver::load(
+            cert_path.as_ref().to_path_buf(),
+            key_path.as_ref().to_path_buf(),
+        )?);
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format_err!("build tls config fail: {}", e))?
+            .with_no_client_auth()
+            .with_cert_resolver(certs.clone());
+        Ok(Self {
+            inner: Arc::new(config).into(),
+            certs: Some(certs),
+            identity: None,
+        })
+    }
+
+    /// use a prepared rustls config, `reload` is unsupported
+    pub fn from_config(config: Arc<ServerConfig>) -> Self {
+        Self {
+            inner: config.into(),
+            certs: None,
+            identity: None,
+        }
+    }
+
+    /// require client certificates signed by the CAs in PEM file
+    pub fn with_client_ca<P: AsRef<Path>>(mut self, ca_path: P) -> Result<Self, ProxyError> {
+        let Some(certs) = &self.certs else {
+            bail!("tls acceptor is not loaded from PEM files");
+        };
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca_path.as_ref())? {
+            roots
+                .add(cert)
+                .map_err(|e| format_err!("add ca {} fail: {}", ca_path.as_ref().display(), e))?;
+        }
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+            .build()
+            .map_err(|e| format_err!("build client verifier fail: {}", e))?;
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format_err!("build tls config fail: {}", e))?
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(certs.clone());
+        self.inner = Arc::new(config).into();
+        self.identity.get_or_insert_default();
+        Ok(self)
+    }
+
+    /// map verified client certificates to the client identity, an acceptor loaded
+    /// from PEM files requests no client certificate without `with_client_ca`
+    pub fn with_client_identity(mut self, identity: CertIdentity) -> Result<Self, ProxyError> {
+        // set by `with_client_ca`, a prepared config may verify clients on its own
+        if self.certs.is_some() && self.identity.is_none() {
+            bail!("client identity requires client certificates, see `with_client_ca`");
+        }
+        self.identity = Some(identity);
+        Ok(self)
+    }
+
+    /// identity of the verified client certificate
+    pub(crate) fn peer_identity(&self, conn: &ServerConnection) -> Option<String> {
+        let cert = conn.peer_certificates()?.first()?;
+        self.identity?.extract(cert)
+    }
+
+    /// reload certificate and key from PEM files, the old pair is kept on failure
+    pub fn reload(&self) -> Result<(), ProxyError> {
+        match &self.certs {
+            Some(certs) => certs.reload(),
+            None => bail!("tls acceptor is not loaded from PEM files"),
+        }
+    }
+
+    /// spawn a task reloading certificate and key when the files are modified
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        let certs = self.certs.clone();
+        tokio::spawn(async move {
+            let Some(certs) = certs else {
+                return;
+            };
+            let mut modified = certs.modified();
+            loop {
+                tokio::time::sleep(interval).await;
+                let now = certs.modified();
+                if now == modified {
+                    continue;
+                }
+                match certs.reload() {
+                    Ok(()) => {
+                        info!("reload tls certificate {}", certs.cert_path.display());
+                        modified = now;
+                    }
+                    Err(e) => warn!("{}", e),
+                }
+            }
+        })
+    }
+
+    pub async fn accept<T>(&self, io: T) -> Result<TlsStream<T>, Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.inner.accept(io).await
+    }
+}
+
+struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    fn load(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, ProxyError> {
+        let current = load_certified_key(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    fn reload(&self) -> Result<(), ProxyError> {
+        let certified = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(certified);
+        Ok(())
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+        (modified(&self.cert_path), modified(&self.key_path))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish()
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, ProxyError> {
+    let certs = load_certs(cert_path)?;
+    let key = any_supported_type(&load_private_key(key_path)?)
+        .map_err(|e| format_err!("invalid private key {}: {}", key_path.display(), e))?;
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match().map_err(|e| {
+        format_err!(
+            "private key {} does not match {}: {}",
+            key_path.display(),
+            cert_path.display(),
+            e
+        )
+    })?;
+    Ok(certified)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::ClientConfig;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+    use tokio_rustls::TlsConnector;
+
+    use super::{CertIdentity, TlsAcceptor};
+    use crate::testing::{TempDir, self_signed, write_pem};
+
+    #[tokio::test]
+    async fn test_accept_and_reload() {
+        let dir = TempDir::new("tls");
+        let (pem, first) = self_signed(&dir, "cert", &["localhost"]);
+        let acceptor = TlsAcceptor::from_pem_files(&pem.cert, &pem.key).unwrap();
+        assert_eq!(
+            handshake(&acceptor, first.cert.der().clone(), None).await,
+            None
+        );
+
+        let (_, second) = self_signed(&dir, "cert", &["localhost"]);
+        acceptor.reload().unwrap();
+        assert_eq!(
+            handshake(&acceptor, second.cert.der().clone(), None).await,
+            None
+        );
+
+        std::fs::write(&pem.key, "broken").unwrap();
+        assert!(acceptor.reload().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_mismatched_key() {
+        let dir = TempDir::new("tls-mismatch");
+        let (pem, first) = self_signed(&dir, "cert", &["localhost"]);
+        let acceptor = TlsAcceptor::from_pem_files(&pem.cert, &pem.key).unwrap();
+
+        // a new certificate written before its key
+        let (other, _) = self_signed(&dir, "other", &["localhost"]);
+        std::fs::copy(&other.cert, &pem.cert).unwrap();
+        assert!(acceptor.reload().is_err());
+        assert!(TlsAcceptor::from_pem_files(&pem.cert, &pem.key).is_err());
+        assert_eq!(
+            handshake(&acceptor, first.cert.der().clone(), None).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_identity() {
+        let dir = TempDir::new("mtls");
+        let (pem, server) = self_signed(&dir, "cert", &["localhost"]);
+        let server_cert = server.cert.der().clone();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let ca_pem = write_pem(&dir, "ca", &ca, &ca_key);
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["device-1.fleet".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "device-1");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client_cert = params.signed_by(&client_key, &ca, &ca_key).unwrap();
+        let client_auth: (_, PrivateKeyDer<'static>) = (
+            client_cert.der().clone(),
+            PrivatePkcs8KeyDer::from(client_key.serialize_der()).into(),
+        );
+
+        let acceptor = TlsAcceptor::from_pem_files(&pem.cert, &pem.key).unwrap();
+        assert!(
+            acceptor
+                .clone()
+                .with_client_identity(CertIdentity::CommonName)
+                .is_err()
+        );
+        let acceptor = acceptor.with_client_ca(&ca_pem.cert).unwrap();
+        let user = handshake(
+            &acceptor,
+            server_cert.clone(),
+            Some((client_auth.0.clone(), client_auth.1.clone_key())),
+        )
+        .await;
+        assert_eq!(user.as_deref(), Some("device-1"));
+
+        let acceptor = acceptor
+            .with_client_identity(CertIdentity::SubjectAltName)
+            .unwrap();
+        let user = handshake(&acceptor, server_cert, Some(client_auth)).await;
+        assert_eq!(user.as_deref(), Some("device-1.fleet"));
+    }
+
+    async fn handshake(
+        acceptor: &TlsAcceptor,
+        trusted: CertificateDer<'static>,
+        client_auth: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+    ) -> Option<String> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(trusted).unwrap();
+        let builder =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+        let config = match client_auth {
+            Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let (client, server) = duplex(4096);
+        let connector = TlsConnector::from(Arc::new(config));
+        let (client, server) = tokio::join!(
+            connector.connect(ServerName::try_from("localhost").unwrap(), client),
+            acceptor.accept(server),
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        client.write_all(b"ping").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        acceptor.peer_identity(server.get_ref().1)
+    }
+}
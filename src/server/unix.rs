@@ -0,0 +1,56 @@
+use std::io::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::net::{UnixListener, UnixStream};
+use tokio_stream::Stream;
+
+/// address reported for unix socket clients, which have no ip address
+pub const UNIX_CLIENT_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// incoming connections of a unix socket listener, clients are reported as
+/// `UNIX_CLIENT_ADDR`, so per-ip limits apply to all of them together
+pub struct UnixIncoming {
+    listener: UnixListener,
+}
+
+impl UnixIncoming {
+    /// bind `path`, a stale socket file left by a previous process is removed
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Self::from_listener(bind_unix(path.as_ref())?))
+    }
+
+    pub fn from_listener(listener: UnixListener) -> Self {
+        Self { listener }
+    }
+}
+
+/// bind `path`, removing a stale socket file first
+pub(crate) fn bind_unix(path: &Path) -> Result<UnixListener, Error> {
+    if let Ok(meta) = std::fs::symlink_metadata(path)
+        && meta.file_type().is_socket()
+    {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+impl Stream for UnixIncoming {
+    type Item = Result<(UnixStream, SocketAddr), Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let (sock, _) = ready!(self.listener.poll_accept(cx))?;
+        Poll::Ready(Some(Ok((sock, UNIX_CLIENT_ADDR))))
+    }
+}
+
+impl AsFd for UnixIncoming {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
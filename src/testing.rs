@@ -0,0 +1,145 @@
+//! fixtures shared by the unit tests
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "tls")]
+use rcgen::{Certificate, CertifiedKey, KeyPair};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::connector::Connector;
+use crate::server::ProxyServer;
+
+/// directory under the system temp dir, removed on drop
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` must be unique among the tests of the crate
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("proxies-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub(crate) fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// paths of a certificate and its key written as PEM
+#[cfg(feature = "tls")]
+pub(crate) struct PemFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// write `{name}.pem` and `{name}.key` into `dir`
+#[cfg(feature = "tls")]
+pub(crate) fn write_pem(dir: &TempDir, name: &str, cert: &Certificate, key: &KeyPair) -> PemFiles {
+    let files = PemFiles {
+        cert: dir.join(format!("{name}.pem")),
+        key: dir.join(format!("{name}.key")),
+    };
+    std::fs::write(&files.cert, cert.pem()).unwrap();
+    std::fs::write(&files.key, key.serialize_pem()).unwrap();
+    files
+}
+
+/// self-signed certificate for `names`, written like `write_pem`
+#[cfg(feature = "tls")]
+pub(crate) fn self_signed(dir: &TempDir, name: &str, names: &[&str]) -> (PemFiles, CertifiedKey) {
+    let names = names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    let certified = rcgen::generate_simple_self_signed(names).unwrap();
+    let files = write_pem(dir, name, &certified.cert, &certified.key_pair);
+    (files, certified)
+}
+
+/// proxy server on an ephemeral local port
+pub(crate) async fn bind<C>(connector: C) -> ProxyServer<C>
+where
+    C: Connector + Send + Sync + 'static,
+    C::Transport: Unpin + Send,
+{
+    ProxyServer::bind(connector, "127.0.0.1:0").await.unwrap()
+}
+
+/// run `server` in the background, returns its address
+pub(crate) fn spawn<C>(server: ProxyServer<C>) -> SocketAddr
+where
+    C: Connector + Send + Sync + 'static,
+    C::Transport: Unpin + Send,
+{
+    let addr = server.incoming().local_addr().unwrap();
+    tokio::spawn(server.run());
+    addr
+}
+
+/// target writing `pong` to each connection
+pub(crate) async fn pong_target() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let _ = sock.write_all(b"pong").await;
+        }
+    });
+    addr
+}
+
+/// target echoing each connection until it closes
+pub(crate) async fn echo_target() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while let Ok(n @ 1..) = sock.read(&mut buf).await {
+                    if sock.write_all(&buf[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// connection to `proxy` that sent a CONNECT for `target`
+async fn send_connect(proxy: SocketAddr, target: impl Display) -> TcpStream {
+    let mut sock = TcpStream::connect(proxy).await.unwrap();
+    let connect = format!("CONNECT {target} HTTP/1.1\r\n\r\n");
+    sock.write_all(connect.as_bytes()).await.unwrap();
+    sock
+}
+
+/// CONNECT tunnel to `target`, `None` if the proxy refuses it
+pub(crate) async fn open_tunnel(proxy: SocketAddr, target: impl Display) -> Option<TcpStream> {
+    let mut sock = send_connect(proxy, target).await;
+    let mut buf = [0; 19];
+    sock.read_exact(&mut buf).await.ok()?;
+    (&buf == b"HTTP/1.1 200 Ok\r\n\r\n").then_some(sock)
+}
+
+/// everything the proxy replies to a CONNECT for `target` until it closes
+pub(crate) async fn request(proxy: SocketAddr, target: impl Display) -> Vec<u8> {
+    let mut sock = send_connect(proxy, target).await;
+    let mut data = Vec::new();
+    sock.read_to_end(&mut data).await.unwrap();
+    data
+}
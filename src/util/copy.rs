@@ -1,11 +1,17 @@
 use std::io::Error;
 use std::pin::Pin;
 use std::string::ToString;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{future::Future, io::ErrorKind};
 
 use pin_project_lite::pin_project;
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader, ReadHalf, WriteHalf, split};
+use tokio::time::{Instant, Sleep, sleep};
+
+use crate::util::TokenBucket;
 
 pin_project! {
     struct Copy<S, D> {
@@ -19,6 +25,12 @@ pin_project! {
         writer_done: bool,
         flush_on_pending: bool,
         amt: usize,
+
+        limits: Vec<Arc<TokenBucket>>,
+        // bytes taken from the buckets but not written yet
+        credit: usize,
+        throttle: Option<Pin<Box<Sleep>>>,
+        counters: Vec<Arc<AtomicU64>>,
     }
 }
 
@@ -38,6 +50,10 @@ impl<S, D> Copy<S, D> {
             writer_done: false,
             flush_on_pending,
             amt: 0,
+            limits: Vec::new(),
+            credit: 0,
+            throttle: None,
+            counters: Vec::new(),
         }
     }
 }
@@ -94,6 +110,34 @@ where
                     buffer.len()
                 );
             }
+            let mut buffer = buffer;
+            if !this.limits.is_empty() {
+                if *this.credit == 0 && this.throttle.is_none() {
+                    let grant = this
+                        .limits
+                        .iter()
+                        .map(|b| b.grant_size())
+                        .fold(buffer.len(), usize::min);
+                    let wait = this.limits.iter().map(|b| b.take(grant)).max();
+                    *this.credit = grant;
+                    if let Some(wait) = wait.filter(|w| !w.is_zero()) {
+                        *this.throttle = Some(Box::pin(sleep(wait)));
+                    }
+                }
+                if let Some(throttle) = this.throttle.as_mut() {
+                    if throttle.as_mut().poll(cx).is_pending() {
+                        if write_flush {
+                            if let Err(e) = ready!(this.writer.as_mut().poll_flush(cx)) {
+                                bail_other_err!("flush {} fail: {}", this.writer_label, e);
+                            }
+                            *this.flush_on_pending = false;
+                        }
+                        return Poll::Pending;
+                    }
+                    *this.throttle = None;
+                }
+                buffer = &buffer[..buffer.len().min(*this.credit)];
+            }
             match ready!(this.writer.as_mut().poll_write(cx, buffer)) {
                 Ok(n) => {
                     if n == 0 {
@@ -102,6 +146,10 @@ where
                     write_flush = true;
                     this.reader.as_mut().consume(n);
                     *this.amt += n;
+                    *this.credit = this.credit.saturating_sub(n);
+                    for counter in this.counters.iter() {
+                        counter.fetch_add(n as u64, Ordering::Relaxed);
+                    }
                 }
                 Err(e) => {
                     bail_other_err!("write {} fail: {}", this.writer_label, e);
@@ -119,6 +167,8 @@ pub struct DuplexCopy<L, R> {
     right: Copy<BufReader<ReadHalf<R>>, WriteHalf<L>>,
     right_done: bool,
     right_amt: usize,
+
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
 }
 
 impl<L, R> DuplexCopy<L, R>
@@ -171,8 +221,38 @@ where
             ),
             right_done: false,
             right_amt: 0,
+
+            idle: None,
         }
     }
+
+    /// shape data read from left and written to right by `bucket`,
+    /// may be called repeatedly to draw from several buckets
+    pub fn with_left_limit(mut self, bucket: Arc<TokenBucket>) -> Self {
+        self.left.limits.push(bucket);
+        self
+    }
+
+    /// shape data read from right and written to left by `bucket`
+    pub fn with_right_limit(mut self, bucket: Arc<TokenBucket>) -> Self {
+        self.right.limits.push(bucket);
+        self
+    }
+
+    /// add bytes copied from left to `left` and from right to `right` as they are written,
+    /// may be called repeatedly to update several counters
+    pub fn with_counters(mut self, left: Arc<AtomicU64>, right: Arc<AtomicU64>) -> Self {
+        self.left.counters.push(left);
+        self.right.counters.push(right);
+        self
+    }
+
+    /// fail with `ErrorKind::TimedOut` when no data is copied in both directions for `timeout`,
+    /// time spent waiting on the limits does not count
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle = Some((timeout, Box::pin(sleep(timeout))));
+        self
+    }
 }
 
 impl<L, R> Future for DuplexCopy<L, R>
@@ -183,6 +263,7 @@ where
     type Output = Result<(usize, usize), Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let copied = self.left.amt + self.right.amt;
         loop {
             if !self.left_done {
                 match Pin::new(&mut self.left).poll(cx) {
@@ -215,9 +296,19 @@ where
         }
 
         if self.left_done && self.right_done {
-            Poll::Ready(Ok((self.left_amt, self.right_amt)))
-        } else {
-            Poll::Pending
+            return Poll::Ready(Ok((self.left_amt, self.right_amt)));
+        }
+        let progress = self.left.amt + self.right.amt != copied;
+        // data waiting for tokens still flows, only slower than the limit allows
+        let shaped = self.left.throttle.is_some() || self.right.throttle.is_some();
+        if let Some((timeout, sleep)) = &mut self.idle {
+            if progress || shaped {
+                sleep.as_mut().reset(Instant::now() + *timeout);
+            }
+            if sleep.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(Error::new(ErrorKind::TimedOut, "idle timeout")));
+            }
         }
+        Poll::Pending
     }
 }
@@ -1,5 +1,11 @@
 mod bufio;
+mod cidr;
 mod copy;
+mod rate;
+#[cfg(feature = "tls")]
+pub(crate) mod tls;
 
 pub use bufio::BufIoExt;
+pub use cidr::Cidr;
 pub use copy::DuplexCopy;
+pub use rate::TokenBucket;
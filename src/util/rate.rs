@@ -0,0 +1,71 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// max bytes granted to one writer at a time, so writers sharing a bucket take turns
+const MAX_GRANT: usize = 16 * 1024;
+
+/// byte rate limit shared by any number of copy directions
+///
+/// tokens are borrowed ahead of time: a writer takes its grant even if the bucket
+/// runs into debt and then waits until the debt is paid, so concurrent writers are
+/// served in the order they asked
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// bytes per second
+    rate: u64,
+    /// max bytes accumulated while idle
+    burst: u64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// `rate` bytes per second, bursts up to `burst` bytes
+    pub fn new(rate: u64, burst: u64) -> Self {
+        assert!(rate > 0, "token bucket rate must be positive");
+        let burst = burst.max(1);
+        Self {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+
+    /// bytes a writer may take at once from this bucket
+    pub(crate) fn grant_size(&self) -> usize {
+        (self.burst as usize).min(MAX_GRANT)
+    }
+
+    /// take `n` bytes, returns how long to wait before they may be sent
+    pub(crate) fn take(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(state.last).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        state.last = now;
+        state.tokens -= n as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate as f64)
+        }
+    }
+}